@@ -0,0 +1,71 @@
+use std::sync::Arc;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use tokio::runtime::Runtime;
+
+use quiz::game_engine::types::{GameSession, PlayerId, SessionRepository};
+use quiz::services::session_journal::FileSessionRepository;
+use quiz::services::sessions::InMemorySessionRepository;
+
+const PLAYERS: usize = 10_000;
+
+fn player_id(num: usize) -> PlayerId {
+    PlayerId {
+        channel_id: "1".to_string(),
+        id: num.to_string(),
+    }
+}
+
+/// Every player reads its session and stores the next version, all at once.
+async fn answer_all<R: SessionRepository + 'static>(repo: Arc<R>) {
+    let tasks: Vec<_> = (0..PLAYERS)
+        .map(|num| {
+            let repo = repo.clone();
+            tokio::spawn(async move {
+                let player_id = player_id(num);
+                let session = repo
+                    .get_by_id(1, &player_id)
+                    .await
+                    .unwrap_or_else(|| GameSession::new(&player_id, 1));
+                repo.store(&session).await.unwrap();
+            })
+        })
+        .collect();
+    for task in tasks {
+        task.await.unwrap();
+    }
+}
+
+fn concurrent_players(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let mut group = c.benchmark_group("concurrent_players");
+    group.throughput(Throughput::Elements(PLAYERS as u64));
+    for shards in [1, 16, 64] {
+        let repo = Arc::new(InMemorySessionRepository::default().with_shards(shards));
+        group.bench_with_input(BenchmarkId::new("shards", shards), &repo, |b, repo| {
+            b.iter(|| runtime.block_on(answer_all(repo.clone())))
+        });
+    }
+    group.finish();
+}
+
+/// The journal store with its default fsync after every write.
+fn concurrent_players_journal(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let mut group = c.benchmark_group("concurrent_players_journal");
+    group.throughput(Throughput::Elements(PLAYERS as u64));
+    group.sample_size(10);
+    for shards in [1, 16, 64] {
+        let dir = std::env::temp_dir().join(format!("quiz-bench-journal-{}", shards));
+        std::fs::create_dir_all(&dir).unwrap();
+        let repo = Arc::new(FileSessionRepository::load_with_shards(&dir, shards).unwrap());
+        group.bench_with_input(BenchmarkId::new("shards", shards), &repo, |b, repo| {
+            b.iter(|| runtime.block_on(answer_all(repo.clone())))
+        });
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+    group.finish();
+}
+
+criterion_group!(benches, concurrent_players, concurrent_players_journal);
+criterion_main!(benches);
@@ -1,5 +1,6 @@
 mod types;
 
+#[allow(dead_code)]
 #[derive(PartialEq, Debug, Clone)]
 struct AdminUser {
     pub login: String,
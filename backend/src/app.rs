@@ -0,0 +1,192 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use hyper::{Body, Request, Response};
+
+use crate::fb_hook_srv::{InboundEvent, InboundMessage, MessageHandler};
+use crate::game_engine::engine::GameEngine;
+use crate::game_engine::types::{
+    DefinitionsRepository, GameApplicationContext, PlayerId, PlayerInput, PlayerMessage,
+    ResponseSender, SessionRepository,
+};
+use crate::services::definitions::FileRepository;
+use crate::services::response::FbResponseService;
+
+/// Feeds webhook messages into the game engine.
+pub struct HandlerAdapter {
+    engine: GameEngine,
+    ctx: &'static dyn GameApplicationContext,
+}
+
+impl HandlerAdapter {
+    pub fn new(ctx: &'static dyn GameApplicationContext) -> Arc<HandlerAdapter> {
+        Arc::new(HandlerAdapter {
+            engine: Default::default(),
+            ctx,
+        })
+    }
+}
+
+#[async_trait]
+impl MessageHandler for HandlerAdapter {
+    async fn process_message(&self, message: InboundMessage) {
+        log::debug!("Processing {:?}", message);
+        self.engine
+            .process_message(
+                PlayerMessage {
+                    player_id: PlayerId {
+                        channel_id: message.to,
+                        id: message.from,
+                    },
+                    input: to_player_input(message.event),
+                },
+                self.ctx,
+            )
+            .await;
+    }
+
+    async fn process_other(&self, _request: Request<Body>) -> Response<Body> {
+        Response::builder().status(404).body(Body::empty()).unwrap()
+    }
+}
+
+fn to_player_input(event: InboundEvent) -> PlayerInput {
+    match event {
+        InboundEvent::Text(text) => PlayerInput::Text(text),
+        InboundEvent::QuickReply { text, payload } => PlayerInput::Payload { payload, text },
+        InboundEvent::Postback { title, payload } => PlayerInput::Payload {
+            payload,
+            text: title,
+        },
+        InboundEvent::Referral { .. } => PlayerInput::Referral,
+    }
+}
+
+pub struct WebApplicationContext {
+    responder: FbResponseService,
+    sessions: Box<dyn SessionRepository>,
+    definitions: FileRepository,
+}
+
+impl WebApplicationContext {
+    pub fn new(
+        responder: FbResponseService,
+        sessions: Box<dyn SessionRepository>,
+        definitions: FileRepository,
+    ) -> WebApplicationContext {
+        WebApplicationContext {
+            responder,
+            sessions,
+            definitions,
+        }
+    }
+}
+
+impl GameApplicationContext for WebApplicationContext {
+    fn responder(&self) -> &dyn ResponseSender {
+        &self.responder
+    }
+
+    fn sessions(&self) -> &dyn SessionRepository {
+        self.sessions.as_ref()
+    }
+
+    fn definitions(&self) -> &dyn DefinitionsRepository {
+        &self.definitions
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::path::PathBuf;
+
+    use hmac::{Hmac, Mac};
+    use hyper::{Body, Request};
+    use serde_json::json;
+    use sha2::Sha256;
+
+    use crate::app::{HandlerAdapter, WebApplicationContext};
+    use crate::fake_graph_srv::FakeGraphServer;
+    use crate::fb_hook_srv::FacebookHookServer;
+    use crate::game_engine::types::ResponseMessage::*;
+    use crate::game_engine::types::{
+        DefinitionsRepository, ResponseMessage, ResponseTextFormatter,
+    };
+    use crate::services::definitions::FileRepository;
+    use crate::services::response::{FbResponseService, GraphApiSettings};
+    use crate::services::sessions::InMemorySessionRepository;
+
+    fn games_dir() -> PathBuf {
+        std::env::current_dir()
+            .unwrap()
+            .join("src")
+            .join("test_resources")
+            .join("games")
+    }
+
+    fn signed_push(num: usize, from: &str, text: &str) -> Request<Body> {
+        let body = json!({
+            "object": "page",
+            "entry": [{
+                "id": "#id1",
+                "time": num,
+                "messaging": [{
+                    "sender": {"id": from},
+                    "recipient": {"id": "#id1"},
+                    "timestamp": num,
+                    "message": {"mid": format!("m_{}", num), "text": text}
+                }]
+            }]
+        })
+        .to_string();
+        let mut mac = Hmac::<Sha256>::new_from_slice(b"SECRET").unwrap();
+        mac.update(body.as_bytes());
+        Request::builder()
+            .method("POST")
+            .uri("/api/webhook")
+            .header(
+                "X-Hub-Signature-256",
+                format!("sha256={}", hex::encode(mac.finalize().into_bytes())),
+            )
+            .body(Body::from(body))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn conversation_is_answered_through_graph_api() {
+        let graph = FakeGraphServer::start(SocketAddr::from(([127, 0, 0, 1], 0)))
+            .await
+            .unwrap();
+        let responder = FbResponseService::with_settings(GraphApiSettings {
+            base_url: graph.base_url(),
+            api_version: "v12.0".to_string(),
+            allow_http: true,
+        });
+        let definitions = FileRepository::load(games_dir().as_path()).await.unwrap();
+        let game = definitions.get_game_by_id(1).await.unwrap();
+        let ctx: &'static WebApplicationContext = Box::leak(Box::new(WebApplicationContext::new(
+            responder,
+            Box::new(InMemorySessionRepository::default()),
+            definitions,
+        )));
+        let server = FacebookHookServer::new_sync("TOKEN", "SECRET", HandlerAdapter::new(ctx));
+
+        for (num, text) in ["hello", "yes", "topic1", "ans11"].iter().enumerate() {
+            let response = server.router(signed_push(num, "42", text)).await.unwrap();
+            assert_eq!(200, response.status().as_u16());
+        }
+
+        let expected: Vec<String> = vec![
+            Greeting(game.name.clone()),
+            Rules(game.topic_keys()),
+            AnswerQuestion("q11".to_string()),
+            Correct(1, 1, None),
+            ChooseNextTopic,
+        ]
+        .into_iter()
+        .map(|m: ResponseMessage| game.format(m))
+        .collect();
+        assert_eq!(expected, graph.texts("42"));
+    }
+}
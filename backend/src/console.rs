@@ -1,15 +1,12 @@
-use std::io::Write;
+use std::sync::Arc;
 
 use async_trait::async_trait;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::runtime::{Builder, Runtime};
 
 use quiz::game_engine::engine::GameEngine;
 use quiz::game_engine::game_def::Game;
-use quiz::game_engine::types::ResponseMessage::*;
 use quiz::game_engine::types::{
     Channel, ChannelId, DefinitionsRepository, GameApplicationContext, GameId, PlayerId,
-    PlayerMessage, ResponseMessage, ResponseSender, ResponseTextFormatter, SessionRepository,
+    PlayerInput, PlayerMessage, RespondError, Response, ResponseSender, SessionRepository,
 };
 use quiz::services::sessions::InMemorySessionRepository;
 
@@ -20,7 +17,7 @@ async fn main() {
     loop {
         line.clear();
         std::io::stdin().read_line(&mut line).expect("Failed");
-        if line == ":q" {
+        if line.trim_end() == ":q" {
             break;
         }
         app.process(line.as_str()).await;
@@ -29,23 +26,23 @@ async fn main() {
 
 struct ConsoleApp {
     engine: GameEngine,
-    game: Game,
+    game: Arc<Game>,
     repo: InMemorySessionRepository,
-    channel: Channel,
+    channel: Arc<Channel>,
 }
 
 impl ConsoleApp {
     async fn new() -> ConsoleApp {
         ConsoleApp {
             engine: Default::default(),
-            game: create_test_game().await,
+            game: Arc::new(create_test_game().await),
             repo: Default::default(),
-            channel: Channel {
+            channel: Arc::new(Channel {
                 name: "console".to_string(),
                 channel_id: "1".to_string(),
                 token: "".to_string(),
                 game_id: Some(1),
-            },
+            }),
         }
     }
 
@@ -57,7 +54,7 @@ impl ConsoleApp {
                         id: "1".to_string(),
                         channel_id: "channel_id".to_string(),
                     },
-                    text: text.to_string(),
+                    input: PlayerInput::Text(text.to_string()),
                 },
                 self,
             )
@@ -76,24 +73,20 @@ async fn create_test_game() -> Game {
 
 #[async_trait]
 impl ResponseSender for ConsoleApp {
-    async fn respond(
-        &self,
-        to: &PlayerId,
-        response: ResponseMessage,
-        format: &dyn ResponseTextFormatter,
-    ) {
-        println!(format.format(response))
+    async fn respond(&self, response: Response) -> Result<(), RespondError> {
+        println!("{}", response.format.format(response.message));
+        Ok(())
     }
 }
 
 #[async_trait]
 impl DefinitionsRepository for ConsoleApp {
-    async fn get_game_by_id(&self, game_id: GameId) -> Option<&Game> {
-        Some(&self.game)
+    async fn get_game_by_id(&self, _: GameId) -> Option<Arc<Game>> {
+        Some(self.game.clone())
     }
 
-    async fn get_channel_by_id(&self, channel_id: &ChannelId) -> Option<&Channel> {
-        Some(&self.channel)
+    async fn get_channel_by_id(&self, _: &ChannelId) -> Option<Arc<Channel>> {
+        Some(self.channel.clone())
     }
 }
 
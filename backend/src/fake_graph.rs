@@ -0,0 +1,24 @@
+use std::net::SocketAddr;
+
+use quiz::fake_graph_srv::FakeGraphServer;
+
+#[tokio::main]
+async fn main() {
+    env_logger::init();
+    let addr = SocketAddr::from(([0, 0, 0, 0], get_port()));
+    match FakeGraphServer::start(addr).await {
+        Ok(_server) => {
+            if let Err(err) = tokio::signal::ctrl_c().await {
+                log::error!("Failed to wait for shutdown {}", err)
+            }
+        }
+        Err(err) => log::error!("Server failed to start {}", err),
+    }
+}
+
+fn get_port() -> u16 {
+    std::env::var("PORT")
+        .unwrap_or("3022".to_string())
+        .parse()
+        .expect("Invalid port")
+}
@@ -0,0 +1,306 @@
+use std::collections::{HashMap, VecDeque};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+/// An error returned instead of accepting the next matching message.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ScriptedError {
+    /// Only messages to this recipient match. Any message matches if absent.
+    #[serde(default)]
+    pub recipient: Option<String>,
+    pub status: u16,
+    pub code: i64,
+    #[serde(default)]
+    pub message: String,
+}
+
+/// Pretends to be the Graph API Send endpoint (`POST /<version>/me/messages`).
+///
+/// Accepted messages are recorded per recipient and can be read back with
+/// `GET /_messages` or `GET /_messages/<recipient>` and cleared with
+/// `DELETE /_messages`. `POST /_errors` with a `ScriptedError` body makes the
+/// next matching send fail.
+pub struct FakeGraphServer {
+    addr: SocketAddr,
+    state: Arc<FakeGraphState>,
+}
+
+#[derive(Default)]
+struct FakeGraphState {
+    messages: Mutex<HashMap<String, Vec<Value>>>,
+    errors: Mutex<VecDeque<ScriptedError>>,
+    requests: AtomicUsize,
+}
+
+impl FakeGraphServer {
+    /// Starts serving on `addr` in the background. Use port 0 to pick a free port.
+    pub async fn start(addr: SocketAddr) -> anyhow::Result<FakeGraphServer> {
+        let state: Arc<FakeGraphState> = Default::default();
+        let service_state = state.clone();
+        let server = Server::try_bind(&addr)?.serve(make_service_fn(move |_conn| {
+            let state = service_state.clone();
+            async move { Ok::<_, Infallible>(service_fn(move |r| route(state.clone(), r))) }
+        }));
+        let addr = server.local_addr();
+        tokio::spawn(async move {
+            if let Err(err) = server.await {
+                log::error!("Fake Graph API server failed {}", err)
+            }
+        });
+        log::info!("Fake Graph API is listening on {}", addr);
+        anyhow::Ok(FakeGraphServer { addr, state })
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn base_url(&self) -> String {
+        format!("http://{}", self.addr)
+    }
+
+    pub fn fail_next(&self, error: ScriptedError) {
+        self.state.errors.lock().unwrap().push_back(error);
+    }
+
+    /// Messages accepted for `recipient`, oldest first.
+    pub fn messages(&self, recipient: &str) -> Vec<Value> {
+        self.state
+            .messages
+            .lock()
+            .unwrap()
+            .get(recipient)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Texts of the messages accepted for `recipient`, skipping attachments.
+    pub fn texts(&self, recipient: &str) -> Vec<String> {
+        self.messages(recipient)
+            .iter()
+            .filter_map(|m| m["text"].as_str().map(|t| t.to_string()))
+            .collect()
+    }
+
+    /// Number of send requests received, including failed ones.
+    pub fn request_count(&self) -> usize {
+        self.state.requests.load(Ordering::SeqCst)
+    }
+
+    pub fn clear(&self) {
+        self.state.messages.lock().unwrap().clear();
+    }
+}
+
+async fn route(
+    state: Arc<FakeGraphState>,
+    request: Request<Body>,
+) -> Result<Response<Body>, Infallible> {
+    let path = request.uri().path().to_string();
+    let response = match (request.method().clone(), path.as_str()) {
+        (Method::POST, path) if path.ends_with("/me/messages") => handle_send(state, request).await,
+        (Method::GET, "/_messages") => {
+            let messages = state.messages.lock().unwrap().clone();
+            json_response(200, json!(messages))
+        }
+        (Method::GET, path) if path.starts_with("/_messages/") => {
+            let recipient = path.trim_start_matches("/_messages/");
+            let messages = state.messages.lock().unwrap();
+            json_response(
+                200,
+                json!(messages.get(recipient).cloned().unwrap_or_default()),
+            )
+        }
+        (Method::DELETE, "/_messages") => {
+            state.messages.lock().unwrap().clear();
+            json_response(200, json!({}))
+        }
+        (Method::POST, "/_errors") => match read_json::<ScriptedError>(request).await {
+            Some(error) => {
+                state.errors.lock().unwrap().push_back(error);
+                json_response(200, json!({}))
+            }
+            None => error_response(400, 100, "Invalid scripted error"),
+        },
+        _ => error_response(404, 803, "Unknown path"),
+    };
+    Ok(response)
+}
+
+async fn handle_send(state: Arc<FakeGraphState>, request: Request<Body>) -> Response<Body> {
+    let num = state.requests.fetch_add(1, Ordering::SeqCst) + 1;
+    let has_token = request
+        .uri()
+        .query()
+        .unwrap_or_default()
+        .split('&')
+        .any(|p| p.starts_with("access_token=") && p.len() > "access_token=".len());
+    if !has_token {
+        return error_response(400, 190, "An active access token must be used");
+    }
+    let body = match read_json::<Value>(request).await {
+        Some(body) => body,
+        None => return error_response(400, 100, "Invalid JSON"),
+    };
+    let recipient = match body["recipient"]["id"].as_str() {
+        Some(recipient) => recipient.to_string(),
+        None => return error_response(400, 100, "The parameter recipient is required"),
+    };
+    if let Some(error) = take_error(&state, recipient.as_str()) {
+        return error_response(error.status, error.code, error.message.as_str());
+    }
+    let message = body["message"].clone();
+    let mut reply = json!({
+        "recipient_id": recipient,
+        "message_id": format!("m_{}", num),
+    });
+    if message["attachment"]["payload"]["is_reusable"].as_bool() == Some(true) {
+        reply["attachment_id"] = json!(format!("a_{}", num));
+    }
+    state
+        .messages
+        .lock()
+        .unwrap()
+        .entry(recipient)
+        .or_default()
+        .push(message);
+    json_response(200, reply)
+}
+
+fn take_error(state: &FakeGraphState, recipient: &str) -> Option<ScriptedError> {
+    let mut errors = state.errors.lock().unwrap();
+    let position = errors
+        .iter()
+        .position(|e| e.recipient.iter().all(|r| r == recipient))?;
+    errors.remove(position)
+}
+
+async fn read_json<T: for<'de> Deserialize<'de>>(request: Request<Body>) -> Option<T> {
+    let buf = hyper::body::to_bytes(request.into_body()).await.ok()?;
+    serde_json::from_slice(buf.as_ref()).ok()
+}
+
+fn json_response(status: u16, body: Value) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(hyper::header::CONTENT_TYPE, "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
+
+fn error_response(status: u16, code: i64, message: &str) -> Response<Body> {
+    json_response(
+        status,
+        json!({"error": {"message": message, "type": "OAuthException", "code": code}}),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use hyper::{Body, Client, Method, Request};
+    use serde_json::{json, Value};
+
+    use crate::fake_graph_srv::{FakeGraphServer, ScriptedError};
+
+    async fn start() -> FakeGraphServer {
+        FakeGraphServer::start(SocketAddr::from(([127, 0, 0, 1], 0)))
+            .await
+            .unwrap()
+    }
+
+    async fn call(method: Method, url: String, body: Value) -> (u16, Value) {
+        let request = Request::builder()
+            .method(method)
+            .uri(url)
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        let response = Client::new().request(request).await.unwrap();
+        let status = response.status().as_u16();
+        let buf = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        (status, serde_json::from_slice(buf.as_ref()).unwrap())
+    }
+
+    fn text_message(recipient: &str, text: &str) -> Value {
+        json!({
+            "messaging_type": "RESPONSE",
+            "recipient": {"id": recipient},
+            "message": {"text": text}
+        })
+    }
+
+    #[tokio::test]
+    async fn messages_are_recorded_per_recipient() {
+        let server = start().await;
+        let url = format!("{}/v12.0/me/messages?access_token=t", server.base_url());
+        call(Method::POST, url.clone(), text_message("1", "a")).await;
+        call(Method::POST, url.clone(), text_message("2", "b")).await;
+        let (status, reply) = call(Method::POST, url, text_message("1", "c")).await;
+
+        assert_eq!(200, status);
+        assert_eq!(json!("1"), reply["recipient_id"]);
+        assert_eq!(vec!["a", "c"], server.texts("1"));
+        assert_eq!(vec!["b"], server.texts("2"));
+        let (_, recorded) = call(
+            Method::GET,
+            format!("{}/_messages/1", server.base_url()),
+            Value::Null,
+        )
+        .await;
+        assert_eq!(json!([{"text": "a"}, {"text": "c"}]), recorded);
+    }
+
+    #[tokio::test]
+    async fn scripted_errors_are_returned_once() {
+        let server = start().await;
+        let url = format!("{}/v12.0/me/messages?access_token=t", server.base_url());
+        call(
+            Method::POST,
+            format!("{}/_errors", server.base_url()),
+            json!({"recipient": "2", "status": 400, "code": 551, "message": "unavailable"}),
+        )
+        .await;
+        server.fail_next(ScriptedError {
+            recipient: None,
+            status: 500,
+            code: 2,
+            message: "".to_string(),
+        });
+
+        let (status, _) = call(Method::POST, url.clone(), text_message("1", "a")).await;
+        assert_eq!(500, status);
+        let (status, _) = call(Method::POST, url.clone(), text_message("1", "a")).await;
+        assert_eq!(200, status);
+        let (status, reply) = call(Method::POST, url.clone(), text_message("2", "b")).await;
+        assert_eq!(400, status);
+        assert_eq!(json!(551), reply["error"]["code"]);
+        let (status, _) = call(Method::POST, url, text_message("2", "b")).await;
+        assert_eq!(200, status);
+
+        assert_eq!(4, server.request_count());
+        assert_eq!(vec!["a"], server.texts("1"));
+    }
+
+    #[tokio::test]
+    async fn sends_without_token_are_rejected() {
+        let server = start().await;
+        let (status, reply) = call(
+            Method::POST,
+            format!("{}/v12.0/me/messages", server.base_url()),
+            text_message("1", "a"),
+        )
+        .await;
+
+        assert_eq!(400, status);
+        assert_eq!(json!(190), reply["error"]["code"]);
+        assert!(server.messages("1").is_empty());
+    }
+}
@@ -1,17 +1,34 @@
 use std::collections::HashMap;
 use std::convert::Infallible;
+use std::fmt::{Display, Formatter};
 use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::Duration;
 
 use async_trait::async_trait;
+use hmac::{Hmac, Mac};
+use hyper::body::HttpBody;
+use hyper::header::HeaderValue;
 use hyper::service::{make_service_fn, service_fn};
 use hyper::{Body, Method, Request, Response, Server};
 use serde_json::Value;
+use sha2::Sha256;
 use urldecode::decode;
 
+use crate::mailbox::Mailboxes;
+use crate::seen_messages::SeenMessages;
+
+const SIGNATURE_HEADER: &str = "X-Hub-Signature-256";
+const SIGNATURE_PREFIX: &str = "sha256=";
+const DEFAULT_MAX_BODY_SIZE: usize = 1024 * 1024;
+const DEFAULT_SEEN_CAPACITY: usize = 10_000;
+const DEFAULT_SEEN_TTL: Duration = Duration::from_secs(60 * 60);
+
+type HmacSha256 = Hmac<Sha256>;
+
 #[async_trait]
 pub trait MessageHandler: Send + Sync {
-    async fn process_text(&self, message: TextMessage);
+    async fn process_message(&self, message: InboundMessage);
     async fn process_other(&self, request: Request<Body>) -> Response<Body>;
 }
 
@@ -20,7 +37,7 @@ struct NoOpHandler {}
 
 #[async_trait]
 impl MessageHandler for NoOpHandler {
-    async fn process_text(&self, message: TextMessage) {
+    async fn process_message(&self, message: InboundMessage) {
         log::info!("Processing {:?}", message)
     }
 
@@ -32,49 +49,130 @@ impl MessageHandler for NoOpHandler {
 pub struct FacebookHookServer {
     sync: bool,
     token: String,
+    app_secret: String,
+    dev_mode: bool,
+    max_body_size: usize,
     handler: Arc<dyn MessageHandler + Send + Sync>,
+    mailboxes: Mailboxes,
+    seen: SeenMessages,
+}
+
+#[derive(Debug)]
+enum PayloadError {
+    TooLarge(usize),
+    Read(hyper::Error),
+    Json(serde_json::Error),
+    MissingObject,
+    UnexpectedEntry(&'static str),
+}
+
+impl PayloadError {
+    fn status(&self) -> u16 {
+        match self {
+            PayloadError::TooLarge(_) => 413,
+            _ => 400,
+        }
+    }
+}
+
+impl Display for PayloadError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PayloadError::TooLarge(limit) => write!(f, "body exceeds {} bytes", limit),
+            PayloadError::Read(err) => write!(f, "failed to read body: {}", err),
+            PayloadError::Json(err) => write!(f, "invalid json: {}", err),
+            PayloadError::MissingObject => write!(f, "missing object field"),
+            PayloadError::UnexpectedEntry(reason) => write!(f, "unexpected entry: {}", reason),
+        }
+    }
 }
 
 #[derive(Clone, Default, Debug, Eq, PartialEq)]
-pub struct TextMessage {
-    pub text: String,
+pub struct InboundMessage {
+    pub mid: String,
     pub from: String,
     pub to: String,
+    pub timestamp: u64,
+    pub event: InboundEvent,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum InboundEvent {
+    Text(String),
+    QuickReply { text: String, payload: String },
+    Postback { title: String, payload: String },
+    Referral { ref_param: String, source: String },
+}
+
+impl Default for InboundEvent {
+    fn default() -> Self {
+        InboundEvent::Text(Default::default())
+    }
 }
 
 impl Default for FacebookHookServer {
     fn default() -> Self {
-        FacebookHookServer {
-            sync: true,
-            token: "TOKEN".to_string(),
-            handler: Arc::new(NoOpHandler::default()),
-        }
+        FacebookHookServer::new(true, "TOKEN", "SECRET", Arc::new(NoOpHandler::default()))
     }
 }
 
 impl FacebookHookServer {
+    /// Processes events before responding to the webhook call. Meant for tests.
     pub fn new_sync(
         token: &str,
+        app_secret: &str,
         handler: Arc<dyn MessageHandler + Send + Sync>,
     ) -> FacebookHookServer {
-        FacebookHookServer {
-            sync: true,
-            token: token.to_string(),
-            handler,
-        }
+        FacebookHookServer::new(true, token, app_secret, handler)
     }
 
+    /// Responds to the webhook call right away and processes events in the background,
+    /// one at a time per player.
     pub fn new_async(
         token: &str,
+        app_secret: &str,
+        handler: Arc<dyn MessageHandler + Send + Sync>,
+    ) -> FacebookHookServer {
+        FacebookHookServer::new(false, token, app_secret, handler)
+    }
+
+    fn new(
+        sync: bool,
+        token: &str,
+        app_secret: &str,
         handler: Arc<dyn MessageHandler + Send + Sync>,
     ) -> FacebookHookServer {
         FacebookHookServer {
-            sync: false,
+            sync,
             token: token.to_string(),
-            handler,
+            app_secret: app_secret.to_string(),
+            dev_mode: false,
+            max_body_size: DEFAULT_MAX_BODY_SIZE,
+            handler: handler.clone(),
+            mailboxes: Mailboxes::new(handler),
+            seen: SeenMessages::new(DEFAULT_SEEN_CAPACITY, DEFAULT_SEEN_TTL),
         }
     }
 
+    /// Accepts events without a signature header. Signed events are still verified.
+    pub fn with_dev_mode(mut self, dev_mode: bool) -> FacebookHookServer {
+        self.dev_mode = dev_mode;
+        self
+    }
+
+    /// Events with a body larger than `max_body_size` bytes are refused before being buffered.
+    pub fn with_max_body_size(mut self, max_body_size: usize) -> FacebookHookServer {
+        self.max_body_size = max_body_size;
+        self
+    }
+
+    /// Redelivered messages are dropped if their id is among the last `capacity` ids
+    /// seen within `ttl`.
+    pub fn with_dedup_window(mut self, capacity: usize, ttl: Duration) -> FacebookHookServer {
+        self.seen = SeenMessages::new(capacity, ttl);
+        self
+    }
+
     pub async fn start(&'static self, port: u16) -> anyhow::Result<()> {
         let addr = SocketAddr::from(([0, 0, 0, 0], port));
         log::info!("Server is listening on {}", port);
@@ -86,7 +184,8 @@ impl FacebookHookServer {
         anyhow::Ok(())
     }
 
-    async fn router(&self, request: Request<Body>) -> Result<Response<Body>, Infallible> {
+    /// Handles a single request the way `start` does.
+    pub async fn router(&self, request: Request<Body>) -> Result<Response<Body>, Infallible> {
         log::info!("{} {}", request.method(), request.uri());
         let response = match (request.method().clone(), request.uri().path()) {
             (Method::GET, "/api/webhook") => self.handle_subscribe(request).await,
@@ -100,41 +199,73 @@ impl FacebookHookServer {
         let params = get_query(&request);
         if params.contains_key("hub.mode") && params.contains_key("hub.verify_token") {
             let verification = decode(params.get("hub.verify_token").unwrap().to_string());
-            if verification == self.token {
-                if *params.get("hub.mode").unwrap() == "subscribe" {
-                    return Response::builder()
-                        .status(200)
-                        .body(Body::from(params.get("hub.challenge").unwrap().to_string()))
-                        .unwrap();
-                }
+            if verification == self.token && *params.get("hub.mode").unwrap() == "subscribe" {
+                return Response::builder()
+                    .status(200)
+                    .body(Body::from(params.get("hub.challenge").unwrap().to_string()))
+                    .unwrap();
             }
         }
         Response::builder().status(403).body(Body::empty()).unwrap()
     }
 
-    async fn handle_event(&self, mut request: Request<Body>) -> Response<Body> {
-        let messages = parse_push_payload(request.body_mut()).await;
-        if messages.len() > 0 {
-            if self.sync {
-                process_messages(messages, self.handler.clone()).await;
-            } else {
-                let handler = self.handler.clone();
-                tokio::spawn(async move {
-                    process_messages(messages, handler).await;
-                });
+    async fn handle_event(&self, request: Request<Body>) -> Response<Body> {
+        let (parts, body) = request.into_parts();
+        let buf = match read_body(body, self.max_body_size).await {
+            Ok(buf) => buf,
+            Err(err) => return reject_payload(err),
+        };
+        if !self.is_signed(parts.headers.get(SIGNATURE_HEADER), buf.as_ref()) {
+            log::warn!("Rejecting event with missing or invalid signature");
+            return Response::builder().status(403).body(Body::empty()).unwrap();
+        }
+        let messages = match parse_push_payload(buf.as_ref()) {
+            Ok(messages) => messages,
+            Err(err) => return reject_payload(err),
+        };
+        let messages = messages.into_iter().filter(|msg| self.is_new(msg));
+        if self.sync {
+            for msg in messages {
+                self.handler.process_message(msg).await;
             }
+        } else {
+            messages
+                .into_iter()
+                .for_each(|msg| self.mailboxes.deliver(msg));
+        }
+        Response::builder().status(200).body(Body::empty()).unwrap()
+    }
+
+    fn is_new(&self, message: &InboundMessage) -> bool {
+        if message.mid.is_empty() || self.seen.insert(message.mid.as_str()) {
+            return true;
+        }
+        log::debug!("Dropping redelivered message {}", message.mid);
+        false
+    }
+
+    fn is_signed(&self, signature: Option<&HeaderValue>, payload: &[u8]) -> bool {
+        match signature.and_then(|s| s.to_str().ok()) {
+            Some(signature) => is_valid_signature(self.app_secret.as_str(), signature, payload),
+            None => self.dev_mode,
         }
-        return Response::builder().status(200).body(Body::empty()).unwrap();
     }
 }
 
-async fn process_messages(
-    mut messages: Vec<TextMessage>,
-    handler: Arc<dyn MessageHandler + Send + Sync>,
-) {
-    while let Some(msg) = messages.pop() {
-        handler.process_text(msg).await;
+fn is_valid_signature(app_secret: &str, signature: &str, payload: &[u8]) -> bool {
+    if app_secret.is_empty() {
+        return false;
     }
+    let expected = match signature
+        .strip_prefix(SIGNATURE_PREFIX)
+        .and_then(|hex_digest| hex::decode(hex_digest).ok())
+    {
+        Some(expected) => expected,
+        None => return false,
+    };
+    let mut mac = HmacSha256::new_from_slice(app_secret.as_bytes()).unwrap();
+    mac.update(payload);
+    mac.verify_slice(expected.as_slice()).is_ok()
 }
 
 fn get_query<T>(request: &Request<T>) -> HashMap<&str, &str> {
@@ -142,60 +273,126 @@ fn get_query<T>(request: &Request<T>) -> HashMap<&str, &str> {
     querystring::querify(request.uri().query().unwrap_or_default())
         .iter()
         .for_each(|p| {
-            params.insert((*p).0, (*p).1);
+            params.insert(p.0, p.1);
         });
     params
 }
 
-async fn parse_push_payload(data: &mut Body) -> Vec<TextMessage> {
-    let buf = hyper::body::to_bytes(data).await.unwrap();
-    let root: Value = serde_json::from_slice(buf.as_ref()).unwrap();
+fn reject_payload(err: PayloadError) -> Response<Body> {
+    log::warn!("Rejecting event: {}", err);
+    Response::builder()
+        .status(err.status())
+        .body(Body::empty())
+        .unwrap()
+}
+
+async fn read_body(mut body: Body, limit: usize) -> Result<Vec<u8>, PayloadError> {
+    if body.size_hint().lower() > limit as u64 {
+        return Err(PayloadError::TooLarge(limit));
+    }
+    let mut buf = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(PayloadError::Read)?;
+        if buf.len() + chunk.len() > limit {
+            return Err(PayloadError::TooLarge(limit));
+        }
+        buf.extend_from_slice(chunk.as_ref());
+    }
+    Ok(buf)
+}
+
+fn parse_push_payload(buf: &[u8]) -> Result<Vec<InboundMessage>, PayloadError> {
+    let root: Value = serde_json::from_slice(buf).map_err(PayloadError::Json)?;
     log::debug!("New event: {}", root);
-    let object = root["object"].as_str().unwrap_or_default();
-    return if object == "page" || object == "instagram" {
+    let object = root["object"].as_str().ok_or(PayloadError::MissingObject)?;
+    if object == "page" || object == "instagram" {
         extract_messages(root)
     } else {
-        Default::default()
-    };
+        Ok(Default::default())
+    }
 }
 
-fn extract_messages(root: Value) -> Vec<TextMessage> {
+fn extract_messages(root: Value) -> Result<Vec<InboundMessage>, PayloadError> {
+    let entries = root["entry"]
+        .as_array()
+        .ok_or(PayloadError::UnexpectedEntry("entry is not an array"))?;
     let mut result = Vec::new();
-    if let Value::Array(entries) = &root["entry"] {
-        entries.iter().for_each(|e| {
-            if let Value::Array(messages) = &e["messaging"] {
-                messages.iter().for_each(|msg| {
-                    if msg["message"].is_object() {
-                        if let (Some(from), Some(to), Some(text), None) = (
-                            msg["sender"]["id"].as_str(),
-                            msg["recipient"]["id"].as_str(),
-                            msg["message"]["text"].as_str(),
-                            msg["message"]["is_echo"].as_bool(),
-                        ) {
-                            result.push(TextMessage {
-                                text: text.to_string(),
-                                from: from.to_string(),
-                                to: to.to_string(),
-                            })
-                        }
-                    }
+    for e in entries {
+        if !e.is_object() {
+            return Err(PayloadError::UnexpectedEntry("entry item is not an object"));
+        }
+        let messages = match &e["messaging"] {
+            Value::Array(messages) => messages,
+            Value::Null => continue,
+            _ => return Err(PayloadError::UnexpectedEntry("messaging is not an array")),
+        };
+        messages.iter().for_each(|msg| {
+            if let (Some(from), Some(to), Some((mid, event))) = (
+                msg["sender"]["id"].as_str(),
+                msg["recipient"]["id"].as_str(),
+                extract_event(msg),
+            ) {
+                result.push(InboundMessage {
+                    mid: mid.to_string(),
+                    from: from.to_string(),
+                    to: to.to_string(),
+                    timestamp: msg["timestamp"].as_u64().unwrap_or_default(),
+                    event,
                 })
             }
         })
     }
-    result
+    result.sort_by_key(|m| m.timestamp);
+    Ok(result)
+}
+
+fn extract_event(msg: &Value) -> Option<(&str, InboundEvent)> {
+    let message = &msg["message"];
+    let postback = &msg["postback"];
+    let referral = &msg["referral"];
+    if message.is_object() {
+        if message["is_echo"].as_bool().is_some() {
+            return None;
+        }
+        let mid = message["mid"].as_str().unwrap_or_default();
+        let text = message["text"].as_str()?.to_string();
+        let event = match message["quick_reply"]["payload"].as_str() {
+            Some(payload) => InboundEvent::QuickReply {
+                text,
+                payload: payload.to_string(),
+            },
+            None => InboundEvent::Text(text),
+        };
+        Some((mid, event))
+    } else if postback.is_object() {
+        let event = InboundEvent::Postback {
+            title: postback["title"].as_str().unwrap_or_default().to_string(),
+            payload: postback["payload"].as_str()?.to_string(),
+        };
+        Some((postback["mid"].as_str().unwrap_or_default(), event))
+    } else if referral.is_object() {
+        let event = InboundEvent::Referral {
+            ref_param: referral["ref"].as_str().unwrap_or_default().to_string(),
+            source: referral["source"].as_str().unwrap_or_default().to_string(),
+        };
+        Some(("", event))
+    } else {
+        None
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::sync::Arc;
+    use std::sync::{Arc, Mutex};
 
     use async_trait::async_trait;
-    use atomic_refcell::AtomicRefCell;
     use hyper::{Body, Method, Request, Response, Uri};
     use serde_json::Value;
 
-    use crate::fb_hook_srv::{extract_messages, FacebookHookServer, MessageHandler, TextMessage};
+    use crate::fb_hook_srv::{
+        extract_messages, parse_push_payload, FacebookHookServer, InboundEvent, InboundMessage,
+        MessageHandler, PayloadError, SIGNATURE_HEADER,
+    };
 
     async fn body_to_str(body: &mut Body) -> String {
         String::from_utf8(hyper::body::to_bytes(body).await.unwrap().to_vec()).unwrap()
@@ -216,11 +413,31 @@ mod tests {
         serde_json::from_str(buf.as_str()).unwrap()
     }
 
+    fn text_events(texts: Vec<&str>) -> Vec<InboundEvent> {
+        texts
+            .into_iter()
+            .map(|t| InboundEvent::Text(t.to_string()))
+            .collect()
+    }
+
     async fn create_message_event_push() -> Request<Body> {
+        create_signed_event_push("new_message.json", "new_message.sig").await
+    }
+
+    async fn create_signed_event_push(subpath: &str, signature_subpath: &str) -> Request<Body> {
+        Request::builder()
+            .uri(Uri::from_static("/api/webhook"))
+            .method(Method::POST)
+            .header(SIGNATURE_HEADER, get_test_message(signature_subpath).await)
+            .body(Body::from(get_test_message(subpath).await))
+            .unwrap()
+    }
+
+    async fn create_unsigned_event_push(subpath: &str) -> Request<Body> {
         Request::builder()
             .uri(Uri::from_static("/api/webhook"))
             .method(Method::POST)
-            .body(Body::from(get_test_message("new_message.json").await))
+            .body(Body::from(get_test_message(subpath).await))
             .unwrap()
     }
 
@@ -252,35 +469,133 @@ mod tests {
     #[tokio::test]
     async fn user_message_is_processed() {
         let engine = Arc::new(NoOpGameEngine::default());
-        let server = FacebookHookServer::new_sync("TOKEN", engine.clone());
+        let server = FacebookHookServer::new_sync("TOKEN", "SECRET", engine.clone());
         server.handle_event(create_message_event_push().await).await;
 
         assert_eq!(
-            vec![TextMessage {
-                text: "hello".to_string(),
+            vec![InboundMessage {
+                mid: "m_MbsBQat74uEN16mObbUIKTtQdyGV6KWczUWcDNd2YumsWyaiuvNfr_xkpNvCV57040ZiLDRMb2s-iS0eSbmtcA".to_string(),
+                event: InboundEvent::Text("hello".to_string()),
                 from: "4339620206152955".to_string(),
                 to: "106197145160389".to_string(),
+                timestamp: 1642629829902,
             }],
             engine.get_hist()
         )
     }
 
+    #[tokio::test]
+    async fn signed_reply_is_processed() {
+        let engine = Arc::new(NoOpGameEngine::default());
+        let server = FacebookHookServer::new_sync("TOKEN", "SECRET", engine.clone());
+        let response = server
+            .handle_event(create_signed_event_push("reply1.json", "reply1.sig").await)
+            .await;
+
+        assert_eq!(200, response.status().as_u16());
+        assert_eq!(1, engine.get_hist().len())
+    }
+
+    #[tokio::test]
+    async fn event_with_wrong_signature_is_rejected() {
+        let engine = Arc::new(NoOpGameEngine::default());
+        let server = FacebookHookServer::new_sync("TOKEN", "SECRET", engine.clone());
+        let response = server
+            .handle_event(create_signed_event_push("new_message.json", "reply1.sig").await)
+            .await;
+
+        assert_eq!(403, response.status().as_u16());
+        assert!(engine.get_hist().is_empty())
+    }
+
+    #[tokio::test]
+    async fn event_signed_with_another_secret_is_rejected() {
+        let engine = Arc::new(NoOpGameEngine::default());
+        let server = FacebookHookServer::new_sync("TOKEN", "ANOTHER_SECRET", engine.clone());
+        let response = server.handle_event(create_message_event_push().await).await;
+
+        assert_eq!(403, response.status().as_u16());
+        assert!(engine.get_hist().is_empty())
+    }
+
+    #[tokio::test]
+    async fn unsigned_event_is_rejected() {
+        let engine = Arc::new(NoOpGameEngine::default());
+        let server = FacebookHookServer::new_sync("TOKEN", "SECRET", engine.clone());
+        let response = server
+            .handle_event(create_unsigned_event_push("new_message.json").await)
+            .await;
+
+        assert_eq!(403, response.status().as_u16());
+        assert!(engine.get_hist().is_empty())
+    }
+
+    #[tokio::test]
+    async fn unsigned_event_is_accepted_in_dev_mode() {
+        let engine = Arc::new(NoOpGameEngine::default());
+        let server =
+            FacebookHookServer::new_sync("TOKEN", "SECRET", engine.clone()).with_dev_mode(true);
+        let response = server
+            .handle_event(create_unsigned_event_push("new_message.json").await)
+            .await;
+
+        assert_eq!(200, response.status().as_u16());
+        assert_eq!(1, engine.get_hist().len())
+    }
+
+    #[tokio::test]
+    async fn event_with_wrong_signature_is_rejected_in_dev_mode() {
+        let engine = Arc::new(NoOpGameEngine::default());
+        let server =
+            FacebookHookServer::new_sync("TOKEN", "SECRET", engine.clone()).with_dev_mode(true);
+        let response = server
+            .handle_event(create_signed_event_push("new_message.json", "reply1.sig").await)
+            .await;
+
+        assert_eq!(403, response.status().as_u16());
+        assert!(engine.get_hist().is_empty())
+    }
+
+    #[tokio::test]
+    async fn redelivered_message_is_processed_once() {
+        let engine = Arc::new(NoOpGameEngine::default());
+        let server = FacebookHookServer::new_sync("TOKEN", "SECRET", engine.clone());
+        server.handle_event(create_message_event_push().await).await;
+        let response = server.handle_event(create_message_event_push().await).await;
+
+        assert_eq!(200, response.status().as_u16());
+        assert_eq!(1, engine.get_hist().len())
+    }
+
+    #[tokio::test]
+    async fn message_is_processed_again_after_dedup_window() {
+        let engine = Arc::new(NoOpGameEngine::default());
+        let server = FacebookHookServer::new_sync("TOKEN", "SECRET", engine.clone())
+            .with_dedup_window(10, std::time::Duration::ZERO);
+        server.handle_event(create_message_event_push().await).await;
+        server.handle_event(create_message_event_push().await).await;
+
+        assert_eq!(2, engine.get_hist().len())
+    }
+
     #[tokio::test]
     async fn should_ignore_echo_messages() {
         let msg = get_test_msg_obj("echo1.json").await;
-        let result = extract_messages(msg);
+        let result = extract_messages(msg).unwrap();
         assert!(result.is_empty())
     }
 
     #[tokio::test]
     async fn should_extract_normal_messages() {
         let msg = get_test_msg_obj("new_message.json").await;
-        let result = extract_messages(msg);
+        let result = extract_messages(msg).unwrap();
         assert_eq!(
-            vec![TextMessage {
-                text: "hello".to_string(),
+            vec![InboundMessage {
+                mid: "m_MbsBQat74uEN16mObbUIKTtQdyGV6KWczUWcDNd2YumsWyaiuvNfr_xkpNvCV57040ZiLDRMb2s-iS0eSbmtcA".to_string(),
+                event: InboundEvent::Text("hello".to_string()),
                 from: "4339620206152955".to_string(),
                 to: "106197145160389".to_string(),
+                timestamp: 1642629829902,
             }],
             result
         )
@@ -289,35 +604,228 @@ mod tests {
     #[tokio::test]
     async fn should_extract_reply_messages() {
         let msg = get_test_msg_obj("reply1.json").await;
-        let result = extract_messages(msg);
+        let result = extract_messages(msg).unwrap();
         assert_eq!(
-            vec![TextMessage {
-                text: "А где ?".to_string(),
+            vec![InboundMessage {
+                mid: "erwerwerwer".to_string(),
+                event: InboundEvent::Text("А где ?".to_string()),
                 from: "4826337357487893".to_string(),
                 to: "17841451802358813".to_string(),
+                timestamp: 1644942934092,
+            }],
+            result
+        )
+    }
+
+    #[tokio::test]
+    async fn should_extract_quick_reply_payload() {
+        let msg = get_test_msg_obj("quick_reply.json").await;
+        let result = extract_messages(msg).unwrap();
+        assert_eq!(
+            vec![InboundEvent::QuickReply {
+                text: "Yes".to_string(),
+                payload: "YES".to_string(),
+            }],
+            result.into_iter().map(|m| m.event).collect::<Vec<_>>()
+        )
+    }
+
+    #[tokio::test]
+    async fn should_extract_postbacks() {
+        let msg = get_test_msg_obj("postback.json").await;
+        let result = extract_messages(msg).unwrap();
+        assert_eq!(
+            vec![InboundMessage {
+                mid: "m_postback1".to_string(),
+                from: "4339620206152955".to_string(),
+                to: "106197145160389".to_string(),
+                timestamp: 1642629829902,
+                event: InboundEvent::Postback {
+                    title: "Topic 1".to_string(),
+                    payload: "TOPIC:topic1".to_string(),
+                },
             }],
             result
         )
     }
 
+    #[tokio::test]
+    async fn should_extract_referrals() {
+        let msg = get_test_msg_obj("referral.json").await;
+        let result = extract_messages(msg).unwrap();
+        assert_eq!(
+            vec![InboundEvent::Referral {
+                ref_param: "promo".to_string(),
+                source: "SHORTLINK".to_string(),
+            }],
+            result.into_iter().map(|m| m.event).collect::<Vec<_>>()
+        )
+    }
+
+    #[tokio::test]
+    async fn should_order_messages_by_timestamp() {
+        let msg = get_test_msg_obj("unordered_batch.json").await;
+        let texts: Vec<InboundEvent> = extract_messages(msg)
+            .unwrap()
+            .into_iter()
+            .map(|m| m.event)
+            .collect();
+        assert_eq!(text_events(vec!["first", "second", "third"]), texts)
+    }
+
+    #[tokio::test]
+    async fn batch_is_processed_in_order() {
+        let engine = Arc::new(NoOpGameEngine::default());
+        let server =
+            FacebookHookServer::new_sync("TOKEN", "SECRET", engine.clone()).with_dev_mode(true);
+        server
+            .handle_event(create_unsigned_event_push("unordered_batch.json").await)
+            .await;
+        let texts: Vec<InboundEvent> = engine.get_hist().into_iter().map(|m| m.event).collect();
+        assert_eq!(text_events(vec!["first", "second", "third"]), texts)
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn batch_is_processed_in_order_in_background() {
+        let engine = Arc::new(NoOpGameEngine::default());
+        let server =
+            FacebookHookServer::new_async("TOKEN", "SECRET", engine.clone()).with_dev_mode(true);
+        server
+            .handle_event(create_unsigned_event_push("unordered_batch.json").await)
+            .await;
+        server
+            .handle_event(create_unsigned_event_push("new_message.json").await)
+            .await;
+        let mut hist = Vec::new();
+        for _ in 0..100 {
+            hist.append(&mut engine.get_hist());
+            if hist.len() == 4 {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        let texts: Vec<InboundEvent> = hist.into_iter().map(|m| m.event).collect();
+        assert_eq!(
+            text_events(vec!["first", "second", "third", "hello"]),
+            texts
+        )
+    }
+
+    #[tokio::test]
+    async fn should_reject_entry_which_is_not_an_array() {
+        let msg = get_test_msg_obj("malformed_entry.json").await;
+        assert!(matches!(
+            extract_messages(msg),
+            Err(PayloadError::UnexpectedEntry(_))
+        ))
+    }
+
+    #[tokio::test]
+    async fn should_reject_messaging_which_is_not_an_array() {
+        let msg = get_test_msg_obj("malformed_messaging.json").await;
+        assert!(matches!(
+            extract_messages(msg),
+            Err(PayloadError::UnexpectedEntry(_))
+        ))
+    }
+
+    #[tokio::test]
+    async fn should_reject_payload_without_object() {
+        let msg = get_test_message("no_object.json").await;
+        assert!(matches!(
+            parse_push_payload(msg.as_bytes()),
+            Err(PayloadError::MissingObject)
+        ))
+    }
+
+    #[tokio::test]
+    async fn should_reject_truncated_payload() {
+        let msg = get_test_message("truncated.json").await;
+        assert!(matches!(
+            parse_push_payload(msg.as_bytes()),
+            Err(PayloadError::Json(_))
+        ))
+    }
+
+    #[tokio::test]
+    async fn should_ignore_payload_for_other_objects() {
+        let payload = r#"{"object": "user", "entry": {}}"#;
+        assert_eq!(
+            Vec::<InboundMessage>::new(),
+            parse_push_payload(payload.as_bytes()).unwrap()
+        )
+    }
+
+    #[tokio::test]
+    async fn malformed_event_push_should_receive_400() {
+        let engine = Arc::new(NoOpGameEngine::default());
+        let server =
+            FacebookHookServer::new_sync("TOKEN", "SECRET", engine.clone()).with_dev_mode(true);
+        for subpath in [
+            "truncated.json",
+            "no_object.json",
+            "malformed_entry.json",
+            "malformed_messaging.json",
+        ] {
+            let response = server
+                .handle_event(create_unsigned_event_push(subpath).await)
+                .await;
+            assert_eq!(400, response.status().as_u16(), "{}", subpath);
+        }
+        assert!(engine.get_hist().is_empty())
+    }
+
+    #[tokio::test]
+    async fn oversized_event_push_should_receive_413() {
+        let engine = Arc::new(NoOpGameEngine::default());
+        let server =
+            FacebookHookServer::new_sync("TOKEN", "SECRET", engine.clone()).with_max_body_size(64);
+        let response = server.handle_event(create_message_event_push().await).await;
+
+        assert_eq!(413, response.status().as_u16());
+        assert!(engine.get_hist().is_empty())
+    }
+
+    #[tokio::test]
+    async fn streamed_oversized_event_push_should_receive_413() {
+        let engine = Arc::new(NoOpGameEngine::default());
+        let server =
+            FacebookHookServer::new_sync("TOKEN", "SECRET", engine.clone()).with_max_body_size(64);
+        let (mut sender, body) = Body::channel();
+        tokio::spawn(async move {
+            for _ in 0..10 {
+                if sender.send_data("0123456789".into()).await.is_err() {
+                    break;
+                }
+            }
+        });
+        let request = Request::builder()
+            .uri(Uri::from_static("/api/webhook"))
+            .method(Method::POST)
+            .body(body)
+            .unwrap();
+        let response = server.handle_event(request).await;
+
+        assert_eq!(413, response.status().as_u16());
+        assert!(engine.get_hist().is_empty())
+    }
+
     #[derive(Default)]
     pub struct NoOpGameEngine {
-        hist: AtomicRefCell<Vec<TextMessage>>,
+        hist: Mutex<Vec<InboundMessage>>,
     }
 
     impl NoOpGameEngine {
-        pub fn get_hist(&self) -> Vec<TextMessage> {
-            let result = self.hist.borrow_mut().to_vec();
-            self.hist.borrow_mut().clear();
-            result
+        pub fn get_hist(&self) -> Vec<InboundMessage> {
+            std::mem::take(self.hist.lock().unwrap().as_mut())
         }
     }
 
     #[async_trait]
     impl MessageHandler for NoOpGameEngine {
-        async fn process_text(&self, message: TextMessage) {
+        async fn process_message(&self, message: InboundMessage) {
             println!("Processing {:?}", message);
-            self.hist.borrow_mut().push(message);
+            self.hist.lock().unwrap().push(message);
         }
 
         async fn process_other(&self, _: Request<Body>) -> Response<Body> {
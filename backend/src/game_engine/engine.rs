@@ -1,9 +1,8 @@
-use regex::internal::Input;
-
-use crate::game_engine::game_def::{Game, QuestionId};
+use crate::game_engine::game_def::{Game, Media, ReplayPolicy, TopicId, NO_PAYLOAD, YES_PAYLOAD};
 use crate::game_engine::types::ResponseMessage::{
-    AlreadyAnswered, ChooseNextTopic, Correct, GameComplete, Greeting, Incorrect, PleaseRetry,
-    PleaseRetryLimits, Quit, Rephrase, Rules,
+    AlmostCorrect, AlreadyAnswered, AnswerChoice, ChooseNextTopic, Correct, GameComplete, Greeting,
+    Help, Hint, Incorrect, NoMoreHints, PleaseRetry, PleaseRetryLimits, Quit, RepeatQuestion,
+    Rephrase, Rules, Score, Skipped, TimeUp,
 };
 use crate::game_engine::types::SessionState::{
     Answering, ChoosingTopic, Complete, Deciding, New, Terminated,
@@ -12,16 +11,22 @@ use crate::game_engine::types::*;
 use crate::text_util::answer_to_standard;
 use std::sync::Arc;
 
+/// How many times a message is processed when its session keeps changing concurrently.
+const MAX_PROCESS_ATTEMPTS: u8 = 3;
+
 #[derive(Default)]
 pub struct GameEngine {}
 
-#[derive(Clone)]
+/// Processes a single message. Responses are queued and only sent once the
+/// new session state is stored, so processing can be retried on a conflict.
 struct MessageContext {
     player_id: PlayerId,
     message: String,
+    input: PlayerInput,
     game: Arc<Game>,
     channel: Arc<Channel>,
     session: GameSession,
+    outbox: Vec<Response>,
     app_context: &'static dyn GameApplicationContext,
 }
 
@@ -34,25 +39,76 @@ impl MessageContext {
     ) -> MessageContext {
         let game_id = game.id;
         MessageContext {
-            message: answer_to_standard(message.text.as_str()),
+            message: answer_to_standard(message.input.text()),
+            input: message.input,
             player_id: message.player_id.clone(),
             game,
             channel,
             session: GameSession::new(&message.player_id, game_id),
+            outbox: Default::default(),
             app_context,
         }
     }
 
-    async fn respond(&self, response: ResponseMessage) {
-        self.app_context
-            .responder()
-            .respond(Response {
-                to: self.player_id.clone(),
-                channel: self.channel.clone(),
-                message: response,
-                format: self.game.clone(),
-            })
-            .await
+    async fn respond(&mut self, response: ResponseMessage) {
+        self.respond_with_media(response, None).await
+    }
+
+    async fn respond_with_media(&mut self, response: ResponseMessage, media: Option<Media>) {
+        self.outbox.push(Response {
+            to: self.player_id.clone(),
+            channel: self.channel.clone(),
+            quick_replies: self.quick_replies(&response),
+            media,
+            message: response,
+            format: self.game.clone(),
+        });
+    }
+
+    async fn send_responses(&mut self) {
+        let mut unreachable = false;
+        for response in std::mem::take(&mut self.outbox) {
+            match self.app_context.responder().respond(response).await {
+                Ok(_) => {}
+                Err(RespondError::Unreachable(reason)) => {
+                    log::warn!("Player {} is unreachable: {}", self.player_id.id, reason);
+                    unreachable = true;
+                    break;
+                }
+                Err(err) => log::error!("Failed to respond to {}: {}", self.player_id.id, err),
+            }
+        }
+        if unreachable {
+            self.session.unreachable = true;
+            if let Err(err) = self.store_progress().await {
+                log::error!("Failed to mark {} unreachable: {}", self.player_id.id, err);
+            }
+        }
+    }
+
+    fn quick_replies(&self, response: &ResponseMessage) -> Vec<QuickReply> {
+        if !self.game.quick_replies {
+            return Default::default();
+        }
+        match response {
+            Greeting(_) => self.game.yes_no_quick_replies(),
+            Rules(_) | ChooseNextTopic => self
+                .game
+                .topic_quick_replies(|id| !self.session.has_played(id)),
+            AnswerChoice(..) | RepeatQuestion(..) | PleaseRetry | PleaseRetryLimits(_)
+            | Rephrase | Hint(..) | NoMoreHints | Score(..) | Help(_) => {
+                match &self.session.state {
+                    Answering(attempt) if self.game.is_multiple_choice(attempt.question_id) => {
+                        self.game.option_quick_replies(attempt)
+                    }
+                    ChoosingTopic if matches!(response, Score(..) | Help(_)) => self
+                        .game
+                        .topic_quick_replies(|id| !self.session.has_played(id)),
+                    _ => Default::default(),
+                }
+            }
+            _ => Default::default(),
+        }
     }
 
     async fn restore_session(&mut self) {
@@ -66,8 +122,10 @@ impl MessageContext {
         }
     }
 
-    async fn store_progress(&self) {
-        self.app_context.sessions().store(&self.session).await;
+    async fn store_progress(&mut self) -> Result<(), StoreError> {
+        self.app_context.sessions().store(&self.session).await?;
+        self.session.version += 1;
+        Ok(())
     }
 
     async fn greet(&mut self) {
@@ -75,11 +133,32 @@ impl MessageContext {
         self.session.state = Deciding;
     }
 
+    fn is_yes(&self) -> bool {
+        match self.input.payload() {
+            Some(payload) => payload == YES_PAYLOAD,
+            None => self.game.is_yes(self.message.as_str()),
+        }
+    }
+
+    fn is_no(&self) -> bool {
+        match self.input.payload() {
+            Some(payload) => payload == NO_PAYLOAD,
+            None => self.game.is_no(self.message.as_str()),
+        }
+    }
+
+    fn find_topic(&self) -> Option<TopicId> {
+        match self.input.payload() {
+            Some(payload) => self.game.find_topic_by_payload(payload),
+            None => self.game.find_topic(self.message.as_str()),
+        }
+    }
+
     async fn has_user_agreed_to_start(&mut self) {
-        if self.game.is_yes(self.message.as_str()) {
+        if self.is_yes() {
             self.respond(Rules(self.game.topic_keys())).await;
             self.session.state = SessionState::ChoosingTopic;
-        } else if self.game.is_no(self.message.as_str()) {
+        } else if self.is_no() {
             self.respond(Quit).await;
             self.session.state = SessionState::Terminated;
         } else {
@@ -88,22 +167,69 @@ impl MessageContext {
     }
 
     async fn choose_topic(&mut self) {
-        if let Some(topic_id) = self.game.find_topic(self.message.as_str()) {
+        if let Some(topic_id) = self.find_topic() {
             if self.session.has_played(topic_id) {
                 self.respond(AlreadyAnswered).await;
-            } else {
-                let question_id = self.game.get_question_from_topic(topic_id);
-                self.session.state = SessionState::answering(question_id, 0);
-                self.respond(ResponseMessage::AnswerQuestion(
-                    self.game.get_question_text(question_id),
-                ))
-                .await;
+            } else if let Some(question_id) = self.game.get_next_question(topic_id, &[]) {
+                let attempt = AnswerAttempt::new(
+                    question_id,
+                    rand::random(),
+                    self.app_context.clock().now_millis(),
+                );
+                self.ask(attempt).await;
             }
         } else {
             self.respond(Rephrase).await;
         }
     }
 
+    async fn ask(&mut self, attempt: AnswerAttempt) {
+        self.session.state = Answering(attempt.clone());
+        self.respond_with_media(
+            self.game.question_message(&attempt),
+            self.game.get_question_media(attempt.question_id),
+        )
+        .await;
+    }
+
+    /// Asks the next question of the round, or closes the topic when the round is over.
+    async fn finish_question(&mut self, attempt: &AnswerAttempt, points: i16) {
+        let asked = attempt.asked_in_round();
+        let topic_id = attempt.question_id.topic();
+        match self.game.get_next_question(topic_id, &asked) {
+            Some(question_id) => {
+                let next = attempt.next_in_round(
+                    question_id,
+                    rand::random(),
+                    self.app_context.clock().now_millis(),
+                    points,
+                );
+                self.ask(next).await;
+            }
+            None => {
+                self.session.record_round(
+                    topic_id,
+                    attempt.round_score.saturating_add(points),
+                    asked.len() as u8,
+                );
+                self.session.state = ChoosingTopic;
+            }
+        }
+    }
+
+    fn restart_if_requested(&mut self) {
+        if !matches!(self.session.state, Terminated | Complete)
+            || !self.game.is_restart(self.message.as_str())
+        {
+            return;
+        }
+        if self.game.replay == ReplayPolicy::Never && !self.session.results.is_empty() {
+            log::debug!("Player {} can not replay the game", self.player_id.id);
+            return;
+        }
+        self.session.restart();
+    }
+
     async fn check_if_terminated(&mut self) -> bool {
         if self.session.state == Terminated {
             return true;
@@ -111,49 +237,120 @@ impl MessageContext {
         if self.game.is_stop(self.message.as_str()) {
             self.session.state = Terminated;
             self.respond(Quit).await;
-            self.store_progress().await;
             return true;
         }
-        return false;
+        false
     }
 
-    async fn answer_was_correct(&mut self, question_id: QuestionId) {
-        self.session.record(
-            question_id.topic(),
-            self.game.get_bonus(question_id.topic()),
-        );
-        self.respond(Correct(self.session.score)).await;
-        self.session.state = ChoosingTopic;
+    async fn answer_was_correct(&mut self, attempt: &AnswerAttempt) {
+        let now = self.app_context.clock().now_millis();
+        let points = self.game.get_points(attempt, now);
+        let reveal = self
+            .game
+            .reveal_on_correct
+            .then(|| self.game.get_reveal(attempt.question_id));
+        self.respond(Correct(points, self.score_with(attempt, points), reveal))
+            .await;
+        self.finish_question(attempt, points).await;
     }
 
-    async fn answer_was_incorrect(
-        &mut self,
-        max_attempt: u8,
-        num_attempt: u8,
-        question_id: QuestionId,
-    ) {
-        let next_attempt = num_attempt + 1;
-        if next_attempt >= max_attempt {
-            self.respond(Incorrect).await;
-            self.session.state = ChoosingTopic;
-            self.session.record(question_id.topic(), 0);
+    /// The total score once the question in `attempt` scores `points`.
+    fn score_with(&self, attempt: &AnswerAttempt, points: i16) -> i32 {
+        self.session.score + attempt.round_score as i32 + points as i32
+    }
+
+    async fn answer_was_incorrect(&mut self, max_attempt: u8, attempt: AnswerAttempt) {
+        let next_attempt = attempt.retry();
+        if next_attempt.attempt >= max_attempt {
+            let points = self.game.get_miss_points();
+            self.respond(Incorrect(
+                points,
+                self.score_with(&attempt, points),
+                self.game.get_reveal(attempt.question_id),
+            ))
+            .await;
+            self.finish_question(&attempt, points).await;
         } else {
-            self.respond(PleaseRetryLimits(max_attempt - next_attempt))
+            self.respond(PleaseRetryLimits(max_attempt - next_attempt.attempt))
                 .await;
-            self.session.state = SessionState::answering(question_id, next_attempt);
+            self.session.state = Answering(next_attempt);
+        }
+    }
+
+    async fn give_hint(&mut self, attempt: AnswerAttempt) {
+        match self.game.get_next_hint(&attempt) {
+            Some(hint) => {
+                let hint = hint.to_string();
+                let left = self.game.count_hints(attempt.question_id) - attempt.hints - 1;
+                self.session.state = Answering(AnswerAttempt {
+                    hints: attempt.hints + 1,
+                    ..attempt
+                });
+                self.respond(Hint(hint, left)).await;
+            }
+            None => self.respond(NoMoreHints).await,
         }
     }
 
     async fn answer_question(&mut self, attempt: AnswerAttempt) {
         if self
             .game
-            .is_correct_answer(attempt.question_id, self.message.as_str())
+            .is_time_up(&attempt, self.app_context.clock().now_millis())
+        {
+            let points = self.game.get_miss_points();
+            self.respond(TimeUp(
+                points,
+                self.score_with(&attempt, points),
+                self.game.get_reveal(attempt.question_id),
+            ))
+            .await;
+            self.finish_question(&attempt, points).await;
+            return;
+        }
+        if self.game.is_hint(self.message.as_str()) {
+            self.give_hint(attempt).await;
+            return;
+        }
+        if self.game.is_skip(self.message.as_str()) {
+            self.respond(Skipped(self.game.get_reveal(attempt.question_id)))
+                .await;
+            self.finish_question(&attempt, 0).await;
+            return;
+        }
+        let is_multiple_choice = self.game.is_multiple_choice(attempt.question_id);
+        let answer = if is_multiple_choice {
+            match self
+                .game
+                .find_option(&attempt, self.input.text(), self.input.payload())
+            {
+                Some(option) => option,
+                None => {
+                    self.respond(Rephrase).await;
+                    return;
+                }
+            }
+        } else {
+            self.input.text().to_string()
+        };
+        if self
+            .game
+            .is_correct_answer(attempt.question_id, answer.as_str())
         {
-            self.answer_was_correct(attempt.question_id).await;
+            self.answer_was_correct(&attempt).await;
+        } else if !is_multiple_choice
+            && !attempt.almost_correct
+            && self
+                .game
+                .is_almost_correct_answer(attempt.question_id, answer.as_str())
+        {
+            self.session.state = Answering(AnswerAttempt {
+                almost_correct: true,
+                ..attempt
+            });
+            self.respond(AlmostCorrect).await;
         } else {
             if let Some(max_attempt) = self.game.max_attempt {
-                self.answer_was_incorrect(max_attempt, attempt.attempt, attempt.question_id)
-                    .await;
+                self.answer_was_incorrect(max_attempt, attempt).await;
             } else {
                 self.respond(PleaseRetry).await;
             }
@@ -162,7 +359,9 @@ impl MessageContext {
 
     async fn check_if_game_complete(&mut self) {
         if self.game.is_complete(self.session.results.len() as u8) {
-            self.respond(GameComplete(self.session.score)).await;
+            let score = self.session.counted_score(self.game.replay);
+            self.session.final_score = Some(score);
+            self.respond(GameComplete(score)).await;
             self.session.state = Complete;
         } else {
             if self.session.state == ChoosingTopic {
@@ -171,11 +370,54 @@ impl MessageContext {
         }
     }
 
+    /// Answers the score, help and repeat commands, which leave the state as it is.
+    async fn handle_command(&mut self) -> bool {
+        let message = self.message.as_str();
+        match self.session.state.clone() {
+            Answering(_) | ChoosingTopic | Complete if self.game.is_score(message) => {
+                // A finished game reports the score that counts, as GameComplete does.
+                let score = match &self.session.state {
+                    Answering(attempt) => self.session.score + attempt.round_score as i32,
+                    ChoosingTopic => self.session.score,
+                    _ => self.session.counted_score(self.game.replay),
+                };
+                let topics = self
+                    .game
+                    .available_topic_keys(|id| !self.session.has_played(id));
+                self.respond(Score(score, topics)).await;
+            }
+            Deciding | Answering(_) | ChoosingTopic if self.game.is_help(message) => {
+                self.respond(Help(self.game.topic_keys())).await;
+            }
+            Answering(attempt) if self.game.is_repeat(message) => {
+                self.respond_with_media(
+                    RepeatQuestion(
+                        self.game.get_question_text(attempt.question_id),
+                        self.game.get_question_options(&attempt),
+                    ),
+                    self.game.get_question_media(attempt.question_id),
+                )
+                .await;
+            }
+            _ => return false,
+        }
+        true
+    }
+
     pub async fn process(&mut self) {
         self.restore_session().await;
+        self.session.unreachable = false;
+        self.session.last_activity = self.app_context.clock().now_millis() / 1000;
+        if self.input == PlayerInput::Referral && self.session.state != New {
+            return;
+        }
+        self.restart_if_requested();
         if self.check_if_terminated().await {
             return;
         }
+        if self.handle_command().await {
+            return;
+        }
         match &self.session.state {
             New => self.greet().await,
             Deciding => self.has_user_agreed_to_start().await,
@@ -185,15 +427,53 @@ impl MessageContext {
                 self.check_if_game_complete().await;
             }
             Complete => {
-                self.respond(GameComplete(self.session.score)).await;
+                let score = self.session.counted_score(self.game.replay);
+                self.respond(GameComplete(score)).await;
             }
             _ => {}
         }
-        self.store_progress().await;
     }
 }
 
 impl GameEngine {
+    async fn process_with_retries(
+        app_context: &'static dyn GameApplicationContext,
+        game: Arc<Game>,
+        channel: Arc<Channel>,
+        message: PlayerMessage,
+    ) {
+        for _ in 0..MAX_PROCESS_ATTEMPTS {
+            let mut ctx =
+                MessageContext::new(app_context, game.clone(), channel.clone(), message.clone());
+            ctx.process().await;
+            match ctx.store_progress().await {
+                Ok(_) => {}
+                Err(StoreError::Conflict(version)) => {
+                    log::warn!(
+                        "Session of {} changed concurrently to version {}, retrying",
+                        message.player_id.id,
+                        version
+                    );
+                    continue;
+                }
+                Err(err) => {
+                    log::error!(
+                        "Dropping responses to {}: failed to store progress: {}",
+                        message.player_id.id,
+                        err
+                    );
+                    return;
+                }
+            }
+            ctx.send_responses().await;
+            return;
+        }
+        log::error!(
+            "Dropping message from {}: session kept changing concurrently",
+            message.player_id.id
+        );
+    }
+
     pub async fn process_message(
         &self,
         message: PlayerMessage,
@@ -206,8 +486,7 @@ impl GameEngine {
         {
             if let Some(game_id) = channel.game_id {
                 if let Some(game) = app_context.definitions().get_game_by_id(game_id).await {
-                    let mut ctx = MessageContext::new(app_context, game, channel, message);
-                    ctx.process().await;
+                    Self::process_with_retries(app_context, game, channel, message).await;
                 } else {
                     log::debug!(
                         "Ignoring message from {}: game {} not found",
@@ -235,8 +514,13 @@ mod tests {
     use std::sync::Arc;
 
     use crate::game_engine::engine::GameEngine;
+    use crate::game_engine::game_def::{Media, MediaKind, ReplayPolicy};
     use crate::game_engine::types::ResponseMessage::*;
-    use crate::game_engine::types::{PlayerId, PlayerMessage, ResponseMessage};
+    use crate::game_engine::types::SessionState::{Answering, Deciding};
+    use crate::game_engine::types::{
+        AnswerAttempt, GameApplicationContext, PlayedAttempt, PlayerId, PlayerInput, PlayerMessage,
+        QuickReply, RespondError, ResponseMessage, Reveal, SessionState, StoreError, TopicResult,
+    };
     use crate::mock::game::MockContext;
 
     fn make_player_id() -> PlayerId {
@@ -247,21 +531,79 @@ mod tests {
     }
 
     async fn run_against_mock_from_start(messages: Vec<&str>) -> Vec<ResponseMessage> {
-        let app_ctx = Arc::new(MockContext::new().await);
+        run_inputs_against_mock(texts(messages)).await
+    }
+
+    async fn run_inputs_against_mock(inputs: Vec<PlayerInput>) -> Vec<ResponseMessage> {
+        run_inputs_against_game("game-1.json", inputs)
+            .await
+            .results()
+    }
+
+    async fn run_inputs_against_game(game: &str, inputs: Vec<PlayerInput>) -> Arc<MockContext> {
+        let app_ctx = Arc::new(MockContext::with_game(game).await);
+        run_inputs(&app_ctx, inputs).await;
+        app_ctx
+    }
+
+    async fn run_inputs(app_ctx: &Arc<MockContext>, inputs: Vec<PlayerInput>) {
         let engine = GameEngine::default();
         let clone_ctx = Box::leak(Box::new(app_ctx.clone()));
-        for message in messages.iter() {
+        for input in inputs.into_iter() {
             engine
                 .process_message(
                     PlayerMessage {
                         player_id: make_player_id(),
-                        text: message.to_string(),
+                        input,
                     },
                     clone_ctx,
                 )
                 .await
         }
-        app_ctx.results()
+    }
+
+    async fn current_attempt(app_ctx: &Arc<MockContext>, game_id: u32) -> AnswerAttempt {
+        let session = app_ctx
+            .sessions()
+            .get_by_id(game_id, &make_player_id())
+            .await
+            .unwrap();
+        match session.state {
+            Answering(attempt) => attempt,
+            state => panic!("Not answering a question: {:?}", state),
+        }
+    }
+
+    fn texts(messages: Vec<&str>) -> Vec<PlayerInput> {
+        messages
+            .iter()
+            .map(|m| PlayerInput::Text(m.to_string()))
+            .collect()
+    }
+
+    fn reveal(answer: &str, explanation: &str) -> Reveal {
+        Reveal {
+            answer: answer.to_string(),
+            explanation: explanation.to_string(),
+        }
+    }
+
+    fn paris() -> Option<Reveal> {
+        Some(reveal("Paris", "Paris has been the capital since 987."))
+    }
+
+    fn quick_reply(title: &str, payload: &str) -> QuickReply {
+        QuickReply {
+            title: title.to_string(),
+            payload: payload.to_string(),
+        }
+    }
+
+    fn payload(payload: &str, text: &str) -> PlayerInput {
+        PlayerInput::Payload {
+            payload: payload.to_string(),
+            text: text.to_string(),
+        }
     }
 
     async fn run_against_mock_in_session(mut messages: Vec<&str>) -> Vec<ResponseMessage> {
@@ -289,6 +631,407 @@ mod tests {
         )
     }
 
+    #[tokio::test]
+    async fn test_player_who_declined_can_restart() {
+        assert_eq!(
+            vec![
+                ResponseMessage::greeting("#TEST_GAME"),
+                Quit,
+                ResponseMessage::greeting("#TEST_GAME"),
+                ResponseMessage::rules(vec!["topic1", "topic2"]),
+            ],
+            run_against_mock_from_start(vec!["Hello", "no", "restart", "yes"]).await
+        )
+    }
+
+    #[tokio::test]
+    async fn test_completed_game_is_not_replayed_by_default() {
+        assert_eq!(
+            vec![GameComplete(1), GameComplete(1)],
+            run_against_mock_in_session(vec!["topic1", "ans11", "topic2", "no", "no", "restart"])
+                .await
+                .split_off(6)
+        )
+    }
+
+    #[tokio::test]
+    async fn test_completed_game_is_replayed_when_allowed() {
+        let ctx = run_inputs_against_game(
+            "game-2.json",
+            texts(vec![
+                "hello", "yes", "topic1", "ans11", "restart", "yes", "topic1", "no", "no",
+            ]),
+        )
+        .await;
+        assert_eq!(
+            vec![
+                Correct(1, 1, None),
+                GameComplete(1),
+                ResponseMessage::greeting("#TEST_GAME2"),
+                ResponseMessage::rules(vec!["topic1"]),
+                AnswerQuestion("q11".to_string()),
+                PleaseRetryLimits(1),
+                Incorrect(0, 0, reveal("ans11", "")),
+                GameComplete(0),
+            ],
+            ctx.results().split_off(3)
+        );
+        let session = ctx
+            .sessions()
+            .get_by_id(2, &make_player_id())
+            .await
+            .unwrap();
+        assert_eq!(
+            vec![PlayedAttempt {
+                results: vec![TopicResult {
+                    topic_id: 0,
+                    score: 1,
+                    questions: 1,
+                }],
+                score: 1,
+                complete: true,
+            }],
+            session.history
+        );
+        assert_eq!(0, session.counted_score(ReplayPolicy::Allowed));
+        assert_eq!(1, session.counted_score(ReplayPolicy::FirstScoreCounts));
+    }
+
+    #[tokio::test]
+    async fn test_first_finished_attempt_counts_when_replayed() {
+        let ctx = run_inputs_against_game(
+            "first-score.json",
+            texts(vec![
+                "hello", "yes", "topic1", "ans11", "restart", "yes", "topic1", "no", "no", "hi",
+            ]),
+        )
+        .await;
+        assert_eq!(
+            vec![
+                Correct(1, 1, None),
+                GameComplete(1),
+                ResponseMessage::greeting("#TEST_GAME5"),
+                ResponseMessage::rules(vec!["topic1"]),
+                AnswerQuestion("q11".to_string()),
+                PleaseRetryLimits(1),
+                Incorrect(0, 0, reveal("ans11", "")),
+                GameComplete(1),
+                GameComplete(1),
+            ],
+            ctx.results().split_off(3)
+        );
+        let session = ctx
+            .sessions()
+            .get_by_id(5, &make_player_id())
+            .await
+            .unwrap();
+        assert_eq!(0, session.score);
+        assert_eq!(Some(1), session.final_score);
+    }
+
+    #[tokio::test]
+    async fn test_score_of_finished_replay_is_the_score_that_counts() {
+        let ctx = run_inputs_against_game(
+            "first-score.json",
+            texts(vec![
+                "hello", "yes", "topic1", "ans11", "restart", "yes", "topic1", "no", "no",
+            ]),
+        )
+        .await;
+        ctx.results();
+        run_inputs(&ctx, texts(vec!["score"])).await;
+
+        assert_eq!(vec![Score(1, vec![])], ctx.results());
+    }
+
+    #[tokio::test]
+    async fn test_misspelled_answer_gets_another_try() {
+        let ctx = run_inputs_against_game(
+            "game-2.json",
+            texts(vec!["hello", "yes", "topic1", "Abc 11", "Anz-11"]),
+        )
+        .await;
+        assert_eq!(
+            vec![AlmostCorrect, Correct(1, 1, None), GameComplete(1)],
+            ctx.results().split_off(3)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_misspelled_answer_gets_only_one_more_try() {
+        let ctx = run_inputs_against_game(
+            "game-2.json",
+            texts(vec!["hello", "yes", "topic1", "Abc 11", "Abc 11", "Abc 11"]),
+        )
+        .await;
+        assert_eq!(
+            vec![
+                AlmostCorrect,
+                PleaseRetryLimits(1),
+                Incorrect(0, 0, reveal("ans11", "")),
+                GameComplete(0),
+            ],
+            ctx.results().split_off(3)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_multiple_choice_is_answered_by_letter_with_stable_options() {
+        let ctx =
+            run_inputs_against_game("choice.json", texts(vec!["hello", "yes", "capitals"])).await;
+        let attempt = current_attempt(&ctx, 3).await;
+        let game = ctx.definitions().get_game_by_id(3).await.unwrap();
+        let options = game.get_question_options(&attempt);
+        let correct = options.iter().position(|o| o == "Paris").unwrap();
+        let wrong = (correct + 1) % options.len();
+        let letter = |position: usize| ((b'a' + position as u8) as char).to_string();
+
+        run_inputs(&ctx, texts(vec!["e", letter(wrong).as_str()])).await;
+        assert_eq!(
+            options,
+            game.get_question_options(&current_attempt(&ctx, 3).await)
+        );
+        run_inputs(&ctx, texts(vec![letter(correct).as_str()])).await;
+
+        let results = ctx.results();
+        assert_eq!(
+            vec![
+                AnswerChoice("Capital of France?".to_string(), options),
+                Rephrase,
+                PleaseRetryLimits(1),
+                Correct(3, 3, paris()),
+                GameComplete(3),
+            ],
+            results.split_at(2).1
+        );
+        let replies = ctx.quick_replies();
+        let letters: Vec<String> = replies[2].iter().map(|r| r.title.clone()).collect();
+        assert_eq!(vec!["A", "B", "C", "D"], letters);
+        assert_eq!(replies[2], replies[4]);
+    }
+
+    #[tokio::test]
+    async fn test_multiple_choice_is_answered_by_number_text_or_payload() {
+        for input in [
+            payload("OPTION:1", "B"),
+            PlayerInput::Text("paris".to_string()),
+        ] {
+            let ctx = run_inputs_against_game(
+                "choice.json",
+                vec![
+                    PlayerInput::Text("hello".to_string()),
+                    PlayerInput::Text("yes".to_string()),
+                    PlayerInput::Text("capitals".to_string()),
+                    input,
+                ],
+            )
+            .await;
+            assert_eq!(
+                vec![Correct(3, 3, paris()), GameComplete(3)],
+                ctx.results().split_off(3)
+            );
+        }
+
+        let ctx =
+            run_inputs_against_game("choice.json", texts(vec!["hello", "yes", "capitals"])).await;
+        let attempt = current_attempt(&ctx, 3).await;
+        let game = ctx.definitions().get_game_by_id(3).await.unwrap();
+        let position = game
+            .get_question_options(&attempt)
+            .iter()
+            .position(|o| o == "Paris")
+            .unwrap();
+        run_inputs(&ctx, texts(vec![(position + 1).to_string().as_str()])).await;
+        assert_eq!(
+            vec![Correct(3, 3, paris()), GameComplete(3)],
+            ctx.results().split_off(3)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_hints_reduce_the_bonus() {
+        let ctx = run_inputs_against_game(
+            "choice.json",
+            texts(vec![
+                "hello",
+                "yes",
+                "capitals",
+                "hint",
+                "подсказка",
+                "hint",
+                "Paris",
+            ]),
+        )
+        .await;
+        assert_eq!(
+            vec![
+                Hint("It is on the Seine".to_string(), 1),
+                Hint("It has the Eiffel Tower".to_string(), 0),
+                NoMoreHints,
+                Correct(1, 1, paris()),
+                GameComplete(1),
+            ],
+            ctx.results().split_off(3)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_faster_answers_score_more() {
+        let ctx =
+            run_inputs_against_game("choice.json", texts(vec!["hello", "yes", "capitals"])).await;
+        ctx.advance_clock(10_000);
+        run_inputs(&ctx, texts(vec!["hint"])).await;
+        ctx.advance_clock(5_000);
+        run_inputs(&ctx, texts(vec!["Paris"])).await;
+        assert_eq!(
+            vec![
+                Hint("It is on the Seine".to_string(), 1),
+                Correct(1, 1, paris()),
+                GameComplete(1)
+            ],
+            ctx.results().split_off(3)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_late_answer_is_recorded_as_failed() {
+        let ctx =
+            run_inputs_against_game("choice.json", texts(vec!["hello", "yes", "capitals"])).await;
+        ctx.advance_clock(30_001);
+        run_inputs(&ctx, texts(vec!["Paris"])).await;
+        assert_eq!(
+            vec![TimeUp(0, 0, paris().unwrap()), GameComplete(0)],
+            ctx.results().split_off(3)
+        );
+        let session = ctx
+            .sessions()
+            .get_by_id(3, &make_player_id())
+            .await
+            .unwrap();
+        assert_eq!(
+            vec![TopicResult {
+                topic_id: 0,
+                score: 0,
+                questions: 1,
+            }],
+            session.results
+        );
+    }
+
+    #[tokio::test]
+    async fn test_topic_is_closed_after_its_round() {
+        let ctx = run_inputs_against_game(
+            "rounds.json",
+            texts(vec![
+                "hello", "yes", "math", "a1", "no", "no", "math", "art", "y", "x", "x", "x",
+            ]),
+        )
+        .await;
+        let results = ctx.results();
+        assert_eq!(
+            vec![
+                AnswerQuestion("m1".to_string()),
+                Correct(1, 1, None),
+                AnswerQuestion("m2".to_string()),
+                PleaseRetryLimits(1),
+                Incorrect(-1, 0, reveal("a2", "")),
+                ChooseNextTopic,
+                AlreadyAnswered,
+            ],
+            results[2..9]
+        );
+        assert_eq!(
+            vec![
+                PleaseRetryLimits(1),
+                Correct(1, 1, None),
+                Correct(2, 3, None),
+                Correct(2, 5, None),
+                GameComplete(5)
+            ],
+            results
+                .iter()
+                .skip(9)
+                .filter(|r| !matches!(r, AnswerQuestion(_)))
+                .cloned()
+                .collect::<Vec<_>>()
+        );
+        let mut asked: Vec<ResponseMessage> = results
+            .into_iter()
+            .skip(9)
+            .filter(|r| matches!(r, AnswerQuestion(_)))
+            .collect();
+        asked.sort_by_key(|r| format!("{:?}", r));
+        assert_eq!(
+            vec![
+                AnswerQuestion("r1".to_string()),
+                AnswerQuestion("r2".to_string()),
+                AnswerQuestion("r3".to_string())
+            ],
+            asked
+        );
+        let session = ctx
+            .sessions()
+            .get_by_id(4, &make_player_id())
+            .await
+            .unwrap();
+        assert_eq!(
+            vec![
+                TopicResult {
+                    topic_id: 0,
+                    score: 0,
+                    questions: 2,
+                },
+                TopicResult {
+                    topic_id: 1,
+                    score: 5,
+                    questions: 3,
+                }
+            ],
+            session.results
+        );
+    }
+
+    #[tokio::test]
+    async fn test_commands_work_during_the_game() {
+        let topics = || vec!["topic1".to_string(), "topic2".to_string()];
+        assert_eq!(
+            vec![
+                Help(topics()),
+                AnswerQuestion("q11".to_string()),
+                RepeatQuestion("q11".to_string(), vec![]),
+                Score(0, topics()),
+                Skipped(reveal("ans11", "")),
+                ChooseNextTopic,
+                Score(0, vec!["topic2".to_string()]),
+                Help(topics()),
+                AnswerQuestion("q21".to_string()),
+                Correct(1, 1, None),
+                GameComplete(1),
+                Score(1, vec![]),
+            ],
+            run_against_mock_from_start(vec![
+                "hello", "yes", "help", "topic1", "repeat", "score", "skip", "score", "help",
+                "topic2", "ans2", "score",
+            ])
+            .await
+            .split_off(2)
+        )
+    }
+
+    #[tokio::test]
+    async fn test_repeated_question_keeps_its_attempts() {
+        assert_eq!(
+            vec![
+                AnswerQuestion("q11".to_string()),
+                PleaseRetryLimits(1),
+                RepeatQuestion("q11".to_string(), vec![]),
+                Incorrect(0, 0, reveal("ans11", "")),
+                ChooseNextTopic,
+            ],
+            run_against_mock_in_session(vec!["topic1", "no", "repeat", "no"]).await
+        )
+    }
+
     #[tokio::test]
     async fn test_engine_stops_replying_on_stop_word() {
         assert_eq!(
@@ -310,7 +1053,7 @@ mod tests {
         assert_eq!(
             vec![
                 AnswerQuestion("q11".to_string()),
-                Correct(1),
+                Correct(1, 1, None),
                 ChooseNextTopic,
             ],
             run_against_mock_in_session(vec!["topic1", "ans11"]).await
@@ -323,7 +1066,7 @@ mod tests {
             vec![
                 AnswerQuestion("q11".to_string()),
                 PleaseRetryLimits(1),
-                Incorrect,
+                Incorrect(0, 0, reveal("ans11", "")),
                 ChooseNextTopic,
             ],
             run_against_mock_in_session(vec!["topic1", "no", "no"]).await
@@ -335,10 +1078,10 @@ mod tests {
         assert_eq!(
             vec![
                 AnswerQuestion("q11".to_string()),
-                Correct(1),
+                Correct(1, 1, None),
                 ChooseNextTopic,
                 AnswerQuestion("q21".to_string()),
-                Correct(2),
+                Correct(1, 2, None),
                 GameComplete(2),
             ],
             run_against_mock_in_session(vec!["topic1", "ans11", "topic2", "ans2"]).await
@@ -350,11 +1093,250 @@ mod tests {
         assert_eq!(
             vec![
                 AnswerQuestion("q11".to_string()),
-                Correct(1),
+                Correct(1, 1, None),
                 ChooseNextTopic,
                 AlreadyAnswered,
             ],
             run_against_mock_in_session(vec!["topic1", "ans11", "topic1"]).await
         )
     }
+
+    #[tokio::test]
+    async fn test_engine_matches_yes_payload() {
+        assert_eq!(
+            vec![
+                ResponseMessage::greeting("#TEST_GAME"),
+                ResponseMessage::rules(vec!["topic1", "topic2"]),
+            ],
+            run_inputs_against_mock(vec![
+                PlayerInput::Text("Hello".to_string()),
+                payload("YES", "Sure, let's go"),
+            ])
+            .await
+        )
+    }
+
+    #[tokio::test]
+    async fn test_engine_matches_no_payload() {
+        assert_eq!(
+            vec![ResponseMessage::greeting("#TEST_GAME"), Quit],
+            run_inputs_against_mock(vec![
+                PlayerInput::Text("Hello".to_string()),
+                payload("NO", "yes"),
+            ])
+            .await
+        )
+    }
+
+    #[tokio::test]
+    async fn test_engine_chooses_topic_by_payload() {
+        assert_eq!(
+            vec![AnswerQuestion("q21".to_string())],
+            run_inputs_against_mock(vec![
+                PlayerInput::Text("hello".to_string()),
+                PlayerInput::Text("yes".to_string()),
+                payload("TOPIC:topic2", "Topic 1"),
+            ])
+            .await
+            .split_off(2)
+        )
+    }
+
+    #[tokio::test]
+    async fn test_engine_does_not_match_topic_payload_partially() {
+        assert_eq!(
+            vec![Rephrase],
+            run_inputs_against_mock(vec![
+                PlayerInput::Text("hello".to_string()),
+                PlayerInput::Text("yes".to_string()),
+                payload("TOPIC:topic", "topic1"),
+            ])
+            .await
+            .split_off(2)
+        )
+    }
+
+    #[tokio::test]
+    async fn test_referral_greets_new_player_only() {
+        assert_eq!(
+            vec![ResponseMessage::greeting("#TEST_GAME")],
+            run_inputs_against_mock(vec![PlayerInput::Referral, PlayerInput::Referral]).await
+        )
+    }
+
+    #[tokio::test]
+    async fn test_greeting_offers_yes_and_no() {
+        let ctx = run_inputs_against_game("game-1.json", texts(vec!["hello"])).await;
+        assert_eq!(
+            vec![vec![quick_reply("yes", "YES"), quick_reply("no", "NO")]],
+            ctx.quick_replies()
+        )
+    }
+
+    #[tokio::test]
+    async fn test_only_unplayed_topics_are_offered() {
+        let ctx = run_inputs_against_game(
+            "game-1.json",
+            texts(vec!["hello", "yes", "topic1", "ans11"]),
+        )
+        .await;
+        assert_eq!(
+            vec![
+                vec![quick_reply("yes", "YES"), quick_reply("no", "NO")],
+                vec![
+                    quick_reply("topic1", "TOPIC:topic1"),
+                    quick_reply("topic2", "TOPIC:topic2"),
+                ],
+                vec![],
+                vec![],
+                vec![quick_reply("topic2", "TOPIC:topic2")],
+            ],
+            ctx.quick_replies()
+        )
+    }
+
+    #[tokio::test]
+    async fn test_question_media_is_sent_with_question() {
+        let ctx = run_inputs_against_game(
+            "game-1.json",
+            texts(vec!["hello", "yes", "topic1", "ans11", "topic2"]),
+        )
+        .await;
+        let media = ctx.media();
+        let results = ctx.results();
+        assert_eq!(None, media[2]);
+        assert_eq!(
+            Some(Media {
+                kind: MediaKind::Image,
+                url: "https://example.com/q21.png".to_string(),
+            }),
+            media[5]
+        );
+        assert_eq!(
+            vec![
+                AnswerQuestion("q11".to_string()),
+                AnswerQuestion("q21".to_string())
+            ],
+            vec![results[2].clone(), results[5].clone()]
+        )
+    }
+
+    #[tokio::test]
+    async fn test_quick_replies_are_off_by_default() {
+        let ctx = run_inputs_against_game("game-2.json", texts(vec!["hello", "yes"])).await;
+        assert_eq!(vec![Vec::<QuickReply>::new(), vec![]], ctx.quick_replies())
+    }
+
+    #[tokio::test]
+    async fn test_message_is_processed_again_when_session_changed_concurrently() {
+        let app_ctx = Arc::new(MockContext::new().await);
+        let clone_ctx = Box::leak(Box::new(app_ctx.clone()));
+        let engine = GameEngine::default();
+        app_ctx.conflict_on_store(2);
+        engine
+            .process_message(
+                PlayerMessage {
+                    player_id: make_player_id(),
+                    input: PlayerInput::Text("hello".to_string()),
+                },
+                clone_ctx,
+            )
+            .await;
+
+        assert_eq!(vec![Greeting("#TEST_GAME".to_string())], app_ctx.results());
+        let session = clone_ctx
+            .sessions()
+            .get_by_id(1, &make_player_id())
+            .await
+            .unwrap();
+        assert_eq!(Deciding, session.state);
+        assert_eq!(1, session.version);
+    }
+
+    #[tokio::test]
+    async fn test_nothing_is_sent_when_session_keeps_changing() {
+        let app_ctx = Arc::new(MockContext::new().await);
+        let clone_ctx = Box::leak(Box::new(app_ctx.clone()));
+        app_ctx.conflict_on_store(10);
+        GameEngine::default()
+            .process_message(
+                PlayerMessage {
+                    player_id: make_player_id(),
+                    input: PlayerInput::Text("hello".to_string()),
+                },
+                clone_ctx,
+            )
+            .await;
+
+        assert!(app_ctx.results().is_empty());
+        assert_eq!(
+            None,
+            clone_ctx.sessions().get_by_id(1, &make_player_id()).await
+        );
+    }
+
+    #[tokio::test]
+    async fn test_nothing_is_sent_when_session_is_not_stored() {
+        let app_ctx = Arc::new(MockContext::new().await);
+        let clone_ctx = Box::leak(Box::new(app_ctx.clone()));
+        app_ctx.fail_stores(StoreError::Failed("disk full".to_string()));
+        GameEngine::default()
+            .process_message(
+                PlayerMessage {
+                    player_id: make_player_id(),
+                    input: PlayerInput::Text("hello".to_string()),
+                },
+                clone_ctx,
+            )
+            .await;
+
+        assert!(app_ctx.results().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_player_is_marked_unreachable_when_response_fails() {
+        let app_ctx = Arc::new(MockContext::new().await);
+        let clone_ctx = Box::leak(Box::new(app_ctx.clone()));
+        let engine = GameEngine::default();
+        let message = PlayerMessage {
+            player_id: make_player_id(),
+            input: PlayerInput::Text("hello".to_string()),
+        };
+        app_ctx.fail_responses(RespondError::Unreachable("blocked".to_string()));
+        engine.process_message(message.clone(), clone_ctx).await;
+        let session = clone_ctx
+            .sessions()
+            .get_by_id(1, &make_player_id())
+            .await
+            .unwrap();
+        assert!(session.unreachable);
+        assert_eq!(Deciding, session.state);
+
+        app_ctx.fail_responses(RespondError::Failed("timeout".to_string()));
+        engine.process_message(message, clone_ctx).await;
+        let session = clone_ctx
+            .sessions()
+            .get_by_id(1, &make_player_id())
+            .await
+            .unwrap();
+        assert!(!session.unreachable);
+    }
+
+    #[tokio::test]
+    async fn test_nothing_more_is_sent_to_unreachable_player() {
+        let ctx =
+            run_inputs_against_game("game-1.json", texts(vec!["hello", "yes", "topic1"])).await;
+        ctx.results();
+        ctx.fail_responses(RespondError::Unreachable("blocked".to_string()));
+        run_inputs(&ctx, texts(vec!["ans11"])).await;
+
+        assert_eq!(vec![Correct(1, 1, None)], ctx.results());
+        let session = ctx
+            .sessions()
+            .get_by_id(1, &make_player_id())
+            .await
+            .unwrap();
+        assert!(session.unreachable);
+        assert_eq!(SessionState::ChoosingTopic, session.state);
+    }
 }
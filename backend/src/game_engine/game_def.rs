@@ -1,15 +1,37 @@
-use crate::game_engine::types::{GameId, ResponseMessage, ResponseTextFormatter};
+use crate::game_engine::types::{
+    AnswerAttempt, GameId, QuickReply, ResponseMessage, ResponseTextFormatter, Reveal,
+};
+use crate::text_util::{answer_to_standard, edit_distance, extract_number, words};
+use once_cell::sync::OnceCell;
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+use regex::{Regex, RegexBuilder};
 use serde::{Deserialize, Serialize};
-use std::path::PathBuf;
+use std::collections::HashSet;
+use std::path::Path;
+
+pub const YES_PAYLOAD: &str = "YES";
+pub const NO_PAYLOAD: &str = "NO";
+const TOPIC_PAYLOAD_PREFIX: &str = "TOPIC:";
+const OPTION_PAYLOAD_PREFIX: &str = "OPTION:";
+const MAX_QUICK_REPLIES: usize = 13;
+/// Options are labeled with letters from A to Z.
+const MAX_OPTIONS: usize = 26;
 
 pub type TopicId = u8;
-#[derive(Default, Debug, Copy, Clone, PartialEq)]
+#[derive(Serialize, Deserialize, Default, Debug, Copy, Clone, PartialEq)]
 pub struct QuestionId(u8, u8);
 
 impl QuestionId {
     pub fn topic(&self) -> u8 {
         self.0
     }
+
+    /// Position of the question in its topic.
+    pub fn question(&self) -> u8 {
+        self.1
+    }
 }
 
 #[derive(Default, Serialize, Deserialize, Debug, Clone, PartialEq)]
@@ -22,9 +44,122 @@ pub struct Game {
     pub max_attempt: Option<u8>,
     #[serde(default)]
     responses: ResponseTemplates,
+    #[serde(default)]
+    pub quick_replies: bool,
+    #[serde(default)]
+    pub replay: ReplayPolicy,
+    /// How answers are compared unless a question sets its own policy.
+    #[serde(default)]
+    pub matching: MatchPolicy,
+    /// Points taken from the bonus for every hint used.
+    #[serde(default = "Game::default_hint_cost")]
+    pub hint_cost: u8,
+    /// Seconds to answer a question, unless its topic or the question sets its own limit.
+    #[serde(default)]
+    pub time_limit: Option<u32>,
+    /// Scales the bonus of timed questions by the share of the time left.
+    #[serde(default)]
+    pub speed_scoring: bool,
+    #[serde(default)]
+    pub scoring: ScoringPolicy,
+    /// Correct answers are followed by the explanation too, not only failed ones.
+    #[serde(default)]
+    pub reveal_on_correct: bool,
+}
+
+/// How many points a question is worth beyond the bonus of its topic.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(default)]
+pub struct ScoringPolicy {
+    /// Points taken from the bonus for every attempt after the first.
+    pub attempt_penalty: u8,
+    /// Points taken for a question that was not answered correctly.
+    pub wrong_answer_penalty: u8,
+    /// The fewest points a single question can score.
+    pub min_points: Option<i16>,
+    /// The most points a single question can score.
+    pub max_points: Option<i16>,
+}
+
+impl ScoringPolicy {
+    fn limit(&self, points: i16) -> i16 {
+        let points = self.min_points.map_or(points, |min| points.max(min));
+        self.max_points.map_or(points, |max| points.min(max))
+    }
+}
+
+/// How a player's answer is compared with the correct ones.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum MatchPolicy {
+    /// Equal after removing case and punctuation.
+    #[default]
+    Exact,
+    /// At most `threshold` edits per character of the correct answer.
+    /// Answers within `close` edits per character, twice the threshold by
+    /// default, are reported as almost correct.
+    EditDistance {
+        threshold: f32,
+        #[serde(default)]
+        close: Option<f32>,
+    },
+    /// The same words in any order.
+    TokenSet,
+}
+
+impl MatchPolicy {
+    fn matches(&self, answer: &str, text: &str) -> bool {
+        match self {
+            MatchPolicy::Exact => answer_to_standard(answer) == answer_to_standard(text),
+            MatchPolicy::EditDistance { threshold, .. } => {
+                relative_distance(answer, text) <= *threshold
+            }
+            MatchPolicy::TokenSet => {
+                let answer: HashSet<String> = words(answer).into_iter().collect();
+                let text: HashSet<String> = words(text).into_iter().collect();
+                answer == text
+            }
+        }
+    }
+
+    fn is_close(&self, answer: &str, text: &str) -> bool {
+        match self {
+            MatchPolicy::EditDistance { threshold, close } => {
+                relative_distance(answer, text) <= close.unwrap_or(threshold * 2.0)
+            }
+            MatchPolicy::Exact | MatchPolicy::TokenSet => false,
+        }
+    }
+}
+
+/// Edits needed per character of `answer`, ignoring case, punctuation and spaces.
+fn relative_distance(answer: &str, text: &str) -> f32 {
+    let answer = words(answer).concat();
+    let text = words(text).concat();
+    if answer.is_empty() {
+        return if text.is_empty() { 0.0 } else { f32::MAX };
+    }
+    edit_distance(answer.as_str(), text.as_str()) as f32 / answer.chars().count() as f32
+}
+
+/// Whether a player who finished or quit the game can play it again.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ReplayPolicy {
+    /// Only players who quit before answering anything can start again.
+    #[default]
+    Never,
+    /// The score of the latest attempt counts.
+    Allowed,
+    /// The game can be played again, but the score of the first finished attempt counts.
+    FirstScoreCounts,
 }
 
 impl Game {
+    fn default_hint_cost() -> u8 {
+        1
+    }
+
     pub fn is_yes(&self, text: &str) -> bool {
         self.generic_answers.yes.iter().any(|s| s == text)
     }
@@ -37,6 +172,30 @@ impl Game {
         self.generic_answers.stop.iter().any(|s| s == text)
     }
 
+    pub fn is_restart(&self, text: &str) -> bool {
+        self.generic_answers.restart.iter().any(|s| s == text)
+    }
+
+    pub fn is_hint(&self, text: &str) -> bool {
+        self.generic_answers.hint.iter().any(|s| s == text)
+    }
+
+    pub fn is_skip(&self, text: &str) -> bool {
+        self.generic_answers.skip.iter().any(|s| s == text)
+    }
+
+    pub fn is_repeat(&self, text: &str) -> bool {
+        self.generic_answers.repeat.iter().any(|s| s == text)
+    }
+
+    pub fn is_score(&self, text: &str) -> bool {
+        self.generic_answers.score.iter().any(|s| s == text)
+    }
+
+    pub fn is_help(&self, text: &str) -> bool {
+        self.generic_answers.help.iter().any(|s| s == text)
+    }
+
     pub fn find_topic(&self, text: &str) -> Option<TopicId> {
         self.topics
             .iter()
@@ -44,10 +203,59 @@ impl Game {
             .map(|id| id as TopicId)
     }
 
-    pub fn get_question_from_topic(&self, id: TopicId) -> QuestionId {
-        let num = self.topics[id as usize].questions.len() as u8;
-        let r: u8 = rand::random();
-        QuestionId(id, r % num)
+    pub fn find_topic_by_payload(&self, payload: &str) -> Option<TopicId> {
+        let key = payload.strip_prefix(TOPIC_PAYLOAD_PREFIX)?;
+        self.topics
+            .iter()
+            .position(|t| t.key == key)
+            .map(|id| id as TopicId)
+    }
+
+    pub fn topic_payload(&self, id: TopicId) -> String {
+        format!("{}{}", TOPIC_PAYLOAD_PREFIX, self.topics[id as usize].key)
+    }
+
+    pub fn yes_no_quick_replies(&self) -> Vec<QuickReply> {
+        let mut result = Vec::new();
+        if let Some(yes) = self.generic_answers.yes.first() {
+            result.push(QuickReply {
+                title: yes.clone(),
+                payload: YES_PAYLOAD.to_string(),
+            })
+        }
+        if let Some(no) = self.generic_answers.no.first() {
+            result.push(QuickReply {
+                title: no.clone(),
+                payload: NO_PAYLOAD.to_string(),
+            })
+        }
+        result
+    }
+
+    pub fn topic_quick_replies(&self, is_available: impl Fn(TopicId) -> bool) -> Vec<QuickReply> {
+        (0..self.topics.len() as TopicId)
+            .filter(|id| is_available(*id))
+            .take(MAX_QUICK_REPLIES)
+            .map(|id| QuickReply {
+                title: self.topics[id as usize].key.clone(),
+                payload: self.topic_payload(id),
+            })
+            .collect()
+    }
+
+    /// The next question of a round in the topic after the `asked` ones, if the round is not over.
+    pub fn get_next_question(&self, id: TopicId, asked: &[u8]) -> Option<QuestionId> {
+        let topic = &self.topics[id as usize];
+        let num = topic.questions.len() as u8;
+        if asked.len() >= topic.round_size.clamp(1, num) as usize {
+            return None;
+        }
+        let left: Vec<u8> = (0..num).filter(|q| !asked.contains(q)).collect();
+        let question = match topic.order {
+            QuestionOrder::Sequential => left[0],
+            QuestionOrder::Random => left[rand::random::<usize>() % left.len()],
+        };
+        Some(QuestionId(id, question))
     }
 
     pub fn get_question_text(&self, question_id: QuestionId) -> String {
@@ -56,23 +264,220 @@ impl Game {
             .clone()
     }
 
-    pub async fn load(path: &PathBuf) -> anyhow::Result<Game> {
+    pub fn get_question_media(&self, question_id: QuestionId) -> Option<Media> {
+        self.topics[question_id.0 as usize].questions[question_id.1 as usize]
+            .media
+            .clone()
+    }
+
+    pub async fn load(path: &Path) -> anyhow::Result<Game> {
         let content = tokio::fs::read(path).await?;
         let game: Game = serde_json::from_slice(content.as_slice())?;
+        game.validate()?;
         anyhow::Ok(game)
     }
 
+    fn validate(&self) -> anyhow::Result<()> {
+        if let Some(topic) = self.topics.iter().find(|t| t.questions.is_empty()) {
+            anyhow::bail!("Topic {} has no questions", topic.key);
+        }
+        for question in self.topics.iter().flat_map(|t| t.questions.iter()) {
+            if question.options.len() > MAX_OPTIONS {
+                anyhow::bail!("Too many options for {}", question.text);
+            }
+            let matching = question.matching.unwrap_or(self.matching);
+            if !question.options.is_empty()
+                && !question
+                    .options
+                    .iter()
+                    .any(|o| question.answers.iter().any(|a| a.matches(matching, o)))
+            {
+                anyhow::bail!("None of the options is correct for {}", question.text);
+            }
+            if question.display_answer.is_none()
+                && question.options.is_empty()
+                && question
+                    .answers
+                    .iter()
+                    .all(|a| matches!(a, Answer::Pattern { .. }))
+            {
+                anyhow::bail!(
+                    "No answer to show for {}, set display_answer",
+                    question.text
+                );
+            }
+            for answer in question.answers.iter() {
+                if let Answer::Pattern { regex, compiled } = answer {
+                    compiled.get(regex).map_err(|err| {
+                        anyhow::anyhow!("Invalid answer to {}: {}", question.text, err)
+                    })?;
+                }
+            }
+        }
+        anyhow::Ok(())
+    }
+
+    /// Checks the player's text as they typed it against the answers of the question.
     pub fn is_correct_answer(&self, question_id: QuestionId, text: &str) -> bool {
-        self.topics[question_id.0 as usize].questions[question_id.1 as usize]
-            .answers
-            .iter()
-            .any(|a| a == text)
+        let question = self.get_question(question_id);
+        let matching = question.matching.unwrap_or(self.matching);
+        question.answers.iter().any(|a| a.matches(matching, text))
+    }
+
+    /// Whether an incorrect answer is close enough to suggest checking the spelling.
+    pub fn is_almost_correct_answer(&self, question_id: QuestionId, text: &str) -> bool {
+        let question = self.get_question(question_id);
+        let matching = question.matching.unwrap_or(self.matching);
+        question.answers.iter().any(|a| match a {
+            Answer::Text(answer) => matching.is_close(answer, text),
+            Answer::Pattern { .. } | Answer::Number { .. } => false,
+        })
+    }
+
+    /// The question as asked in `attempt`, listing the options of a multiple choice question.
+    pub fn question_message(&self, attempt: &AnswerAttempt) -> ResponseMessage {
+        let question = self.get_question(attempt.question_id);
+        if question.options.is_empty() {
+            ResponseMessage::AnswerQuestion(question.text.clone())
+        } else {
+            ResponseMessage::AnswerChoice(question.text.clone(), self.get_question_options(attempt))
+        }
+    }
+
+    pub fn is_multiple_choice(&self, question_id: QuestionId) -> bool {
+        !self.get_question(question_id).options.is_empty()
+    }
+
+    /// The options of a multiple choice question in the order shown in `attempt`.
+    pub fn get_question_options(&self, attempt: &AnswerAttempt) -> Vec<String> {
+        let options = &self.get_question(attempt.question_id).options;
+        option_order(options.len(), attempt.seed)
+            .into_iter()
+            .map(|id| options[id].clone())
+            .collect()
+    }
+
+    pub fn option_quick_replies(&self, attempt: &AnswerAttempt) -> Vec<QuickReply> {
+        let num = self.get_question(attempt.question_id).options.len();
+        option_order(num, attempt.seed)
+            .into_iter()
+            .enumerate()
+            .take(MAX_QUICK_REPLIES)
+            .map(|(position, id)| QuickReply {
+                title: option_letter(position).to_string(),
+                payload: format!("{}{}", OPTION_PAYLOAD_PREFIX, id),
+            })
+            .collect()
+    }
+
+    /// The option the player picked by its letter, number or text, or by a quick reply.
+    pub fn find_option(
+        &self,
+        attempt: &AnswerAttempt,
+        text: &str,
+        payload: Option<&str>,
+    ) -> Option<String> {
+        if let Some(payload) = payload {
+            let id: usize = payload.strip_prefix(OPTION_PAYLOAD_PREFIX)?.parse().ok()?;
+            return self
+                .get_question(attempt.question_id)
+                .options
+                .get(id)
+                .cloned();
+        }
+        let options = self.get_question_options(attempt);
+        let text = answer_to_standard(text);
+        if let Some(option) = options.iter().find(|o| answer_to_standard(o) == text) {
+            return Some(option.clone());
+        }
+        let position = match text.parse::<usize>() {
+            Ok(num) => num.checked_sub(1)?,
+            Err(_) => option_position(text.as_str())?,
+        };
+        options.get(position).cloned()
+    }
+
+    fn get_question(&self, question_id: QuestionId) -> &Question {
+        &self.topics[question_id.0 as usize].questions[question_id.1 as usize]
     }
 
     pub fn get_bonus(&self, topic_id: TopicId) -> u8 {
         self.topics[topic_id as usize].bonus
     }
 
+    /// Points for answering correctly in `attempt` at `now` unix millis: the
+    /// bonus less the cost of the hints and the extra attempts used.
+    pub fn get_points(&self, attempt: &AnswerAttempt, now: u64) -> i16 {
+        let penalty = attempt.hints as i16 * self.hint_cost as i16
+            + attempt.attempt as i16 * self.scoring.attempt_penalty as i16;
+        let points = (self.get_bonus(attempt.question_id.topic()) as i16 - penalty).max(0);
+        let points = match self.get_time_limit(attempt.question_id) {
+            Some(limit) if self.speed_scoring && limit > 0 => {
+                let elapsed = now.saturating_sub(attempt.asked_at) as f32 / 1000.0;
+                let left = (1.0 - elapsed / limit as f32).clamp(0.0, 1.0);
+                (points as f32 * left).ceil() as i16
+            }
+            _ => points,
+        };
+        self.scoring.limit(points)
+    }
+
+    /// The correct answer to show once the question is over.
+    pub fn get_reveal(&self, question_id: QuestionId) -> Reveal {
+        let question = self.get_question(question_id);
+        let matching = question.matching.unwrap_or(self.matching);
+        let answer = question.display_answer.clone().or_else(|| {
+            match question
+                .options
+                .iter()
+                .find(|o| question.answers.iter().any(|a| a.matches(matching, o)))
+            {
+                Some(option) => Some(option.clone()),
+                None => question.answers.iter().find_map(|a| match a {
+                    Answer::Text(text) => Some(text.clone()),
+                    Answer::Number { number, .. } => Some(number.to_string()),
+                    Answer::Pattern { .. } => None,
+                }),
+            }
+        });
+        Reveal {
+            answer: answer.unwrap_or_default(),
+            explanation: question.explanation.clone().unwrap_or_default(),
+        }
+    }
+
+    /// Points for a question that was not answered correctly.
+    pub fn get_miss_points(&self) -> i16 {
+        self.scoring
+            .limit(-(self.scoring.wrong_answer_penalty as i16))
+    }
+
+    /// Seconds to answer the question, if it is timed.
+    pub fn get_time_limit(&self, question_id: QuestionId) -> Option<u32> {
+        self.get_question(question_id)
+            .time_limit
+            .or(self.topics[question_id.0 as usize].time_limit)
+            .or(self.time_limit)
+    }
+
+    /// Whether the time limit of the question in `attempt` has passed at `now` unix millis.
+    pub fn is_time_up(&self, attempt: &AnswerAttempt, now: u64) -> bool {
+        self.get_time_limit(attempt.question_id)
+            .is_some_and(|limit| now > attempt.asked_at + limit as u64 * 1000)
+    }
+
+    /// The hint to show after the ones used in `attempt`, if any is left.
+    pub fn get_next_hint(&self, attempt: &AnswerAttempt) -> Option<&str> {
+        self.get_question(attempt.question_id)
+            .hints
+            .get(attempt.hints as usize)
+            .map(|h| h.as_str())
+    }
+
+    pub fn count_hints(&self, question_id: QuestionId) -> u8 {
+        self.get_question(question_id).hints.len() as u8
+    }
+
     pub fn is_complete(&self, num_answers: u8) -> bool {
         self.topics.len() as u8 == num_answers
     }
@@ -80,6 +485,13 @@ impl Game {
     pub fn topic_keys(&self) -> Vec<String> {
         self.topics.iter().map(|t| t.key.clone()).collect()
     }
+
+    pub fn available_topic_keys(&self, is_available: impl Fn(TopicId) -> bool) -> Vec<String> {
+        (0..self.topics.len() as TopicId)
+            .filter(|id| is_available(*id))
+            .map(|id| self.topics[id as usize].key.clone())
+            .collect()
+    }
 }
 
 #[derive(Default, Serialize, Deserialize, Debug, Clone, PartialEq)]
@@ -88,12 +500,175 @@ pub struct Topic {
     key: String,
     questions: Vec<Question>,
     bonus: u8,
+    #[serde(default)]
+    time_limit: Option<u32>,
+    /// Number of questions asked before the topic is closed.
+    #[serde(default = "Topic::default_round_size")]
+    round_size: u8,
+    #[serde(default)]
+    order: QuestionOrder,
+}
+
+impl Topic {
+    fn default_round_size() -> u8 {
+        1
+    }
+}
+
+/// The order questions of a topic are asked in.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum QuestionOrder {
+    #[default]
+    Random,
+    /// As listed in the topic.
+    Sequential,
 }
 
 #[derive(Default, Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct Question {
     text: String,
-    answers: Vec<String>,
+    answers: Vec<Answer>,
+    #[serde(default)]
+    media: Option<Media>,
+    #[serde(default)]
+    matching: Option<MatchPolicy>,
+    /// Makes it a multiple choice question. The answers say which options are correct.
+    #[serde(default)]
+    options: Vec<String>,
+    /// Shown one by one when the player asks for a hint.
+    #[serde(default)]
+    hints: Vec<String>,
+    #[serde(default)]
+    time_limit: Option<u32>,
+    /// Shown once the question is over.
+    #[serde(default)]
+    explanation: Option<String>,
+    /// The answer shown to the player, the first correct option or text answer by default.
+    #[serde(default)]
+    display_answer: Option<String>,
+}
+
+/// Positions of the options as shown to the player, the same for the same seed.
+fn option_order(num: usize, seed: u64) -> Vec<usize> {
+    let mut order: Vec<usize> = (0..num).collect();
+    order.shuffle(&mut StdRng::seed_from_u64(seed));
+    order
+}
+
+fn option_letter(position: usize) -> char {
+    (b'A' + position as u8) as char
+}
+
+/// Cyrillic letters that look like the Latin letters of options, typed on a Russian keyboard.
+const CYRILLIC_LOOKALIKES: [(char, char); 12] = [
+    ('а', 'a'),
+    ('в', 'b'),
+    ('с', 'c'),
+    ('е', 'e'),
+    ('н', 'h'),
+    ('к', 'k'),
+    ('м', 'm'),
+    ('о', 'o'),
+    ('р', 'p'),
+    ('т', 't'),
+    ('х', 'x'),
+    ('у', 'y'),
+];
+
+fn option_position(text: &str) -> Option<usize> {
+    let mut chars = text.chars();
+    let letter = match (chars.next()?, chars.next()) {
+        (letter, None) => CYRILLIC_LOOKALIKES
+            .iter()
+            .find(|(cyrillic, _)| *cyrillic == letter)
+            .map_or(letter, |(_, latin)| *latin),
+        _ => return None,
+    };
+    match letter {
+        'a'..='z' => Some(letter as usize - 'a' as usize),
+        _ => None,
+    }
+}
+
+/// A correct answer to a question.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum Answer {
+    /// Compared according to the matching policy.
+    Text(String),
+    /// A regular expression found anywhere in the text, ignoring case.
+    Pattern {
+        regex: String,
+        #[serde(skip)]
+        compiled: CompiledRegex,
+    },
+    /// A number in digits or words, within `tolerance` of `number`.
+    Number {
+        number: f64,
+        #[serde(default)]
+        tolerance: f64,
+    },
+}
+
+impl Answer {
+    fn matches(&self, matching: MatchPolicy, text: &str) -> bool {
+        match self {
+            Answer::Text(answer) => matching.matches(answer, text),
+            Answer::Pattern { regex, compiled } => compiled
+                .get(regex)
+                .map(|r| r.is_match(text.trim()))
+                .unwrap_or(false),
+            Answer::Number { number, tolerance } => {
+                extract_number(text).is_some_and(|n| (n - number).abs() <= *tolerance)
+            }
+        }
+    }
+}
+
+/// The regex of a pattern answer, compiled when the game is validated on load.
+#[derive(Debug, Clone, Default)]
+pub struct CompiledRegex(OnceCell<Result<Regex, regex::Error>>);
+
+impl CompiledRegex {
+    fn get(&self, regex: &str) -> Result<&Regex, &regex::Error> {
+        self.0
+            .get_or_init(|| RegexBuilder::new(regex).case_insensitive(true).build())
+            .as_ref()
+    }
+}
+
+/// Patterns are compared by their source text.
+impl PartialEq for CompiledRegex {
+    fn eq(&self, _other: &Self) -> bool {
+        true
+    }
+}
+
+/// A picture, a sound or a clip sent to the player before the question text.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Media {
+    #[serde(rename = "type")]
+    pub kind: MediaKind,
+    pub url: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum MediaKind {
+    Image,
+    Audio,
+    Video,
+}
+
+impl MediaKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MediaKind::Image => "image",
+            MediaKind::Audio => "audio",
+            MediaKind::Video => "video",
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
@@ -101,6 +676,44 @@ pub struct GenericAnswers {
     pub yes: Vec<String>,
     pub no: Vec<String>,
     pub stop: Vec<String>,
+    #[serde(default = "GenericAnswers::default_restart")]
+    pub restart: Vec<String>,
+    #[serde(default = "GenericAnswers::default_hint")]
+    pub hint: Vec<String>,
+    #[serde(default = "GenericAnswers::default_skip")]
+    pub skip: Vec<String>,
+    #[serde(default = "GenericAnswers::default_repeat")]
+    pub repeat: Vec<String>,
+    #[serde(default = "GenericAnswers::default_score")]
+    pub score: Vec<String>,
+    #[serde(default = "GenericAnswers::default_help")]
+    pub help: Vec<String>,
+}
+
+impl GenericAnswers {
+    fn default_restart() -> Vec<String> {
+        vec!["restart".to_string(), "заново".to_string()]
+    }
+
+    fn default_hint() -> Vec<String> {
+        vec!["hint".to_string(), "подсказка".to_string()]
+    }
+
+    fn default_skip() -> Vec<String> {
+        vec!["skip".to_string(), "пропустить".to_string()]
+    }
+
+    fn default_repeat() -> Vec<String> {
+        vec!["repeat".to_string(), "повтори".to_string()]
+    }
+
+    fn default_score() -> Vec<String> {
+        vec!["score".to_string(), "счет".to_string()]
+    }
+
+    fn default_help() -> Vec<String> {
+        vec!["help".to_string(), "помощь".to_string()]
+    }
 }
 
 impl Default for GenericAnswers {
@@ -109,11 +722,18 @@ impl Default for GenericAnswers {
             yes: vec!["yes".to_string(), "да".to_string()],
             no: vec!["no".to_string(), "нет".to_string()],
             stop: vec!["stop".to_string(), "стоп".to_string()],
+            restart: Self::default_restart(),
+            hint: Self::default_hint(),
+            skip: Self::default_skip(),
+            repeat: Self::default_repeat(),
+            score: Self::default_score(),
+            help: Self::default_help(),
         }
     }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
 pub struct ResponseTemplates {
     greeting: String,
     rephrase: String,
@@ -127,6 +747,15 @@ pub struct ResponseTemplates {
     choose_next_topic: String,
     already_answered: String,
     quit: String,
+    almost_correct: String,
+    answer_choice: String,
+    hint: String,
+    no_more_hints: String,
+    time_up: String,
+    skipped: String,
+    repeat_question: String,
+    score: String,
+    help: String,
 }
 
 impl ResponseTextFormatter for Game {
@@ -149,11 +778,14 @@ impl ResponseTextFormatter for Game {
                 .responses
                 .please_retry_limits
                 .replace("#LEFT", num.to_string().as_str()),
-            ResponseMessage::Incorrect => self.responses.incorrect.clone(),
-            ResponseMessage::Correct(score) => self
-                .responses
-                .correct
-                .replace("#SCORE", score.to_string().as_str()),
+            ResponseMessage::Incorrect(points, score, reveal) => format_reveal(
+                &format_points(&self.responses.incorrect, points, score),
+                &reveal,
+            ),
+            ResponseMessage::Correct(points, score, reveal) => format_reveal(
+                &format_points(&self.responses.correct, points, score),
+                &reveal.unwrap_or_default(),
+            ),
             ResponseMessage::GameComplete(score) => self
                 .responses
                 .game_complete
@@ -161,10 +793,68 @@ impl ResponseTextFormatter for Game {
             ResponseMessage::ChooseNextTopic => self.responses.choose_next_topic.clone(),
             ResponseMessage::AlreadyAnswered => self.responses.already_answered.clone(),
             ResponseMessage::Quit => self.responses.quit.clone(),
+            ResponseMessage::AlmostCorrect => self.responses.almost_correct.clone(),
+            ResponseMessage::AnswerChoice(text, options) => self
+                .responses
+                .answer_choice
+                .replace("#QUESTION", text.as_str())
+                .replace("#OPTIONS", render_options(&options).as_str()),
+            ResponseMessage::Hint(hint, left) => self
+                .responses
+                .hint
+                .replace("#HINT", hint.as_str())
+                .replace("#LEFT", left.to_string().as_str()),
+            ResponseMessage::NoMoreHints => self.responses.no_more_hints.clone(),
+            ResponseMessage::TimeUp(points, score, reveal) => format_reveal(
+                &format_points(&self.responses.time_up, points, score),
+                &reveal,
+            ),
+            ResponseMessage::Skipped(reveal) => format_reveal(&self.responses.skipped, &reveal),
+            ResponseMessage::RepeatQuestion(text, options) => self
+                .responses
+                .repeat_question
+                .replace("#QUESTION", text.as_str())
+                .replace("#OPTIONS", render_options(&options).as_str())
+                .trim_end()
+                .to_string(),
+            ResponseMessage::Score(score, topics) => self
+                .responses
+                .score
+                .replace("#SCORE", score.to_string().as_str())
+                .replace("#TOPICS", topics.join(", ").as_str()),
+            ResponseMessage::Help(topics) => self
+                .responses
+                .help
+                .replace("#TOPICS", topics.join(", ").as_str()),
         }
     }
 }
 
+fn format_points(template: &str, points: i16, score: i32) -> String {
+    template
+        .replace("#POINTS", points.to_string().as_str())
+        .replace("#SCORE", score.to_string().as_str())
+}
+
+/// Fills in the answer and the explanation, and trims trailing whitespace,
+/// which an empty explanation at the end of the template leaves behind.
+fn format_reveal(template: &str, reveal: &Reveal) -> String {
+    template
+        .replace("#ANSWER", reveal.answer.as_str())
+        .replace("#EXPLANATION", reveal.explanation.as_str())
+        .trim_end()
+        .to_string()
+}
+
+fn render_options(options: &[String]) -> String {
+    options
+        .iter()
+        .enumerate()
+        .map(|(position, option)| format!("{}) {}", option_letter(position), option))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
 impl Default for ResponseTemplates {
     fn default() -> Self {
         ResponseTemplates {
@@ -174,12 +864,263 @@ impl Default for ResponseTemplates {
             answer_question: "Next question: #QUESTION".to_string(),
             please_retry: "That is incorrect. Try again".to_string(),
             please_retry_limits: "That is incorrect. Try again. #LEFT attempts left".to_string(),
-            incorrect: "That is incorrect".to_string(),
-            correct: "That is correct. Your score: #SCORE".to_string(),
+            incorrect: "That is incorrect. The correct answer is #ANSWER. #EXPLANATION".to_string(),
+            correct: "That is correct. Your score: #SCORE\n#EXPLANATION".to_string(),
             game_complete: "Game is complete. Your score: #SCORE".to_string(),
             choose_next_topic: "Choose the next topic".to_string(),
             already_answered: "You already answered this topic".to_string(),
-            quit: "Ok... Goodbye!".to_string()
+            quit: "Ok... Goodbye!".to_string(),
+            almost_correct: "Almost! Check the spelling and try again".to_string(),
+            answer_choice: "Next question: #QUESTION\n#OPTIONS".to_string(),
+            hint: "Hint: #HINT. Hints left: #LEFT".to_string(),
+            no_more_hints: "There are no more hints for this question".to_string(),
+            time_up: "Sorry, the time is up. The correct answer is #ANSWER. #EXPLANATION"
+                .to_string(),
+            skipped: "Skipped. The correct answer is #ANSWER. #EXPLANATION".to_string(),
+            repeat_question: "Once again: #QUESTION\n#OPTIONS".to_string(),
+            score: "Your score: #SCORE. Topics left: #TOPICS".to_string(),
+            help: "Choose a topic from: #TOPICS. Answer the question or send skip to skip it, repeat to see it again, hint for a hint and score for your score".to_string(),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::game_engine::game_def::{Answer, Game, QuestionId};
+    use crate::game_engine::types::{AnswerAttempt, ResponseMessage, ResponseTextFormatter};
+
+    fn make_game(matching: &str) -> Game {
+        serde_json::from_str(
+            format!(
+                r#"{{
+                    "id": 1,
+                    "name": "test",
+                    "max_attempt": 2,
+                    "matching": {},
+                    "topics": [{{
+                        "name": "Books",
+                        "key": "books",
+                        "bonus": 1,
+                        "questions": [
+                            {{"text": "q1", "answers": ["Dostoevsky"]}},
+                            {{"text": "q2", "answers": ["Leo Tolstoy"], "matching": {{"type": "token_set"}}}}
+                        ]
+                    }}]
+                }}"#,
+                matching
+            )
+            .as_str(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn exact_match_ignores_case_and_punctuation() {
+        let game = make_game(r#"{"type": "exact"}"#);
+        assert!(game.is_correct_answer(QuestionId(0, 0), "dostoevsky!"));
+        assert!(!game.is_correct_answer(QuestionId(0, 0), "dostoyevsky"));
+        assert!(!game.is_almost_correct_answer(QuestionId(0, 0), "dostoyevsky"));
+    }
+
+    #[test]
+    fn edit_distance_is_relative_to_answer_length() {
+        let game = make_game(r#"{"type": "edit_distance", "threshold": 0.1}"#);
+        assert!(game.is_correct_answer(QuestionId(0, 0), "Dostoyevsky"));
+        assert!(!game.is_correct_answer(QuestionId(0, 0), "Dastoyevsky"));
+        assert!(game.is_almost_correct_answer(QuestionId(0, 0), "Dastoyevsky"));
+        assert!(!game.is_almost_correct_answer(QuestionId(0, 0), "Tolstoy"));
+    }
+
+    #[test]
+    fn question_policy_overrides_game_policy() {
+        let game = make_game(r#"{"type": "edit_distance", "threshold": 0.1}"#);
+        assert!(game.is_correct_answer(QuestionId(0, 1), "tolstoy, leo"));
+        assert!(!game.is_correct_answer(QuestionId(0, 1), "Leo Tolstoi"));
+        assert!(!game.is_correct_answer(QuestionId(0, 1), "leo"));
+    }
+
+    #[test]
+    fn exact_match_is_the_default() {
+        let game: Game = serde_json::from_str(
+            r#"{"id": 1, "name": "test", "max_attempt": 2, "topics": [], "responses": {"quit": "Bye"}}"#,
+        )
+        .unwrap();
+        assert_eq!(Game::default().matching, game.matching);
+    }
+
+    #[test]
+    fn typed_answers_are_checked() {
+        let game: Game = serde_json::from_str(
+            r#"{
+                "id": 1,
+                "name": "test",
+                "max_attempt": 2,
+                "topics": [{
+                    "name": "History",
+                    "key": "history",
+                    "bonus": 1,
+                    "questions": [{
+                        "text": "When?",
+                        "answers": [{"number": 1812}, {"regex": "^napoleon('s)? invasion$"}]
+                    }, {
+                        "text": "How many?",
+                        "answers": [{"number": 300, "tolerance": 20}]
+                    }]
+                }]
+            }"#,
+        )
+        .unwrap();
+        assert!(game.validate().is_ok());
+        assert!(matches!(
+            &game.topics[0].questions[0].answers[1],
+            Answer::Pattern { compiled, .. } if compiled.0.get().is_some()
+        ));
+        assert!(game.is_correct_answer(QuestionId(0, 0), "in 1812 year"));
+        assert!(game.is_correct_answer(QuestionId(0, 0), "тысяча восемьсот двенадцать"));
+        assert!(game.is_correct_answer(QuestionId(0, 0), " Napoleon's invasion"));
+        assert!(!game.is_correct_answer(QuestionId(0, 0), "1813"));
+        assert!(!game.is_correct_answer(QuestionId(0, 0), "napoleon"));
+        assert!(game.is_correct_answer(QuestionId(0, 1), "about 310"));
+        assert!(game.is_correct_answer(QuestionId(0, 1), "примерно триста"));
+        assert!(!game.is_correct_answer(QuestionId(0, 1), "three hundred and thirty"));
+    }
+
+    #[test]
+    fn invalid_regex_is_rejected() {
+        let game: Game = serde_json::from_str(
+            r#"{"id": 1, "name": "test", "max_attempt": 2, "topics": [{
+                "name": "t", "key": "t", "bonus": 1,
+                "questions": [{"text": "q", "answers": [{"regex": "(unclosed"}], "display_answer": "a"}]
+            }]}"#,
+        )
+        .unwrap();
+        assert!(game
+            .validate()
+            .unwrap_err()
+            .to_string()
+            .starts_with("Invalid answer to q"));
+    }
+
+    #[test]
+    fn pattern_answers_need_an_answer_to_show() {
+        let game: Game = serde_json::from_str(
+            r#"{"id": 1, "name": "test", "max_attempt": 2, "topics": [{
+                "name": "t", "key": "t", "bonus": 1,
+                "questions": [{"text": "q", "answers": [{"regex": "^moscow$"}]}]
+            }]}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            "No answer to show for q, set display_answer",
+            game.validate().unwrap_err().to_string()
+        );
+    }
+
+    #[test]
+    fn options_are_shuffled_by_seed_and_found_by_letter_number_or_text() {
+        let game: Game = serde_json::from_str(
+            r#"{"id": 1, "name": "test", "max_attempt": 2, "topics": [{
+                "name": "t", "key": "t", "bonus": 1,
+                "questions": [{"text": "q", "options": ["a1", "a2", "a3", "a4"], "answers": ["a2"]}]
+            }]}"#,
+        )
+        .unwrap();
+        let attempt = AnswerAttempt::new(QuestionId(0, 0), 42, 0);
+        let options = game.get_question_options(&attempt);
+        assert_eq!(options, game.get_question_options(&attempt.retry()));
+        assert!((0..10).any(|seed| {
+            game.get_question_options(&AnswerAttempt::new(QuestionId(0, 0), seed, 0)) != options
+        }));
+
+        let found = |text: &str| game.find_option(&attempt, text, None);
+        assert_eq!(Some(options[1].clone()), found("B"));
+        assert_eq!(Some(options[1].clone()), found("b)"));
+        assert_eq!(Some(options[2].clone()), found("3"));
+        assert_eq!(Some("a4".to_string()), found("A4"));
+        assert_eq!(None, found("e"));
+        assert_eq!(None, found("0"));
+        assert_eq!(Some(options[0].clone()), found("А"));
+        assert_eq!(Some(options[1].clone()), found("В)"));
+        assert_eq!(Some(options[2].clone()), found("с"));
+        assert_eq!(None, found("б"));
+        assert_eq!(
+            Some("a2".to_string()),
+            game.find_option(&attempt, "x", Some("OPTION:1"))
+        );
+        assert_eq!(
+            "Next question: q\nA) a1\nB) a2",
+            game.format(ResponseMessage::AnswerChoice(
+                "q".to_string(),
+                vec!["a1".to_string(), "a2".to_string()]
+            ))
+        );
+    }
+
+    #[test]
+    fn multiple_choice_needs_a_correct_option() {
+        let game: Game = serde_json::from_str(
+            r#"{"id": 1, "name": "test", "max_attempt": 2, "topics": [{
+                "name": "t", "key": "t", "bonus": 1,
+                "questions": [{"text": "q", "options": ["a1", "a2"], "answers": ["a3"]}]
+            }]}"#,
+        )
+        .unwrap();
+        assert!(game.validate().is_err());
+    }
+
+    #[test]
+    fn points_follow_the_scoring_policy() {
+        let game: Game = serde_json::from_str(
+            r#"{"id": 1, "name": "test", "max_attempt": 5,
+                "scoring": {"attempt_penalty": 1, "wrong_answer_penalty": 2, "min_points": -1, "max_points": 2},
+                "responses": {"correct": "+#POINTS, total #SCORE"},
+                "topics": [{"name": "t", "key": "t", "bonus": 3,
+                            "questions": [{"text": "q", "answers": ["a"]}]}]}"#,
+        )
+        .unwrap();
+        let mut attempt = AnswerAttempt::new(QuestionId(0, 0), 0, 0);
+        assert_eq!(2, game.get_points(&attempt, 0));
+        attempt.attempt = 2;
+        assert_eq!(1, game.get_points(&attempt, 0));
+        attempt.attempt = 4;
+        assert_eq!(0, game.get_points(&attempt, 0));
+        assert_eq!(-1, game.get_miss_points());
+        assert_eq!(
+            "+2, total -3",
+            game.format(ResponseMessage::Correct(2, -3, None))
+        );
+    }
+
+    #[test]
+    fn correct_answer_is_revealed() {
+        let game: Game = serde_json::from_str(
+            r#"{"id": 1, "name": "test", "max_attempt": 2, "topics": [{
+                "name": "t", "key": "t", "bonus": 1,
+                "questions": [
+                    {"text": "q1", "answers": [{"regex": "^18\\d\\d$"}, {"number": 1812}]},
+                    {"text": "q2", "answers": [{"regex": "^moscow$"}], "display_answer": "Moscow",
+                     "explanation": "It burned down."}
+                ]
+            }]}"#,
+        )
+        .unwrap();
+        let reveal = game.get_reveal(QuestionId(0, 0));
+        assert_eq!(
+            "That is incorrect. The correct answer is 1812.",
+            game.format(ResponseMessage::Incorrect(0, 0, reveal))
+        );
+        let reveal = game.get_reveal(QuestionId(0, 1));
+        assert_eq!(
+            "That is incorrect. The correct answer is Moscow. It burned down.",
+            game.format(ResponseMessage::Incorrect(0, 0, reveal.clone()))
+        );
+        assert_eq!(
+            "That is correct. Your score: 3\nIt burned down.",
+            game.format(ResponseMessage::Correct(1, 3, Some(reveal)))
+        );
+        assert_eq!(
+            "That is correct. Your score: 3",
+            game.format(ResponseMessage::Correct(1, 3, None))
+        );
+    }
+}
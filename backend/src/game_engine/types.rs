@@ -1,11 +1,13 @@
+use std::fmt::{Display, Formatter};
 use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use async_trait::async_trait;
 use serde::{Deserialize, Serialize};
 
-use crate::game_engine::game_def::{Game, QuestionId, TopicId};
+use crate::game_engine::game_def::{Game, Media, QuestionId, ReplayPolicy, TopicId};
 use crate::game_engine::types::ResponseMessage::AnswerQuestion;
-use crate::game_engine::types::SessionState::{Answering, New};
+use crate::game_engine::types::SessionState::Answering;
 
 pub type GameId = u32;
 pub type ChannelId = String;
@@ -18,19 +20,34 @@ pub struct Channel {
     pub game_id: Option<GameId>,
 }
 
-#[derive(PartialEq, Debug, Clone, Hash, Eq, Default)]
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Hash, Eq, Default)]
 pub struct PlayerId {
     pub channel_id: String,
     pub id: String,
 }
 
-#[derive(PartialEq, Debug, Clone, Default)]
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Default)]
 pub struct GameSession {
     pub player_id: PlayerId,
     pub game_id: GameId,
     pub state: SessionState,
     pub results: Vec<TopicResult>,
-    pub score: u16,
+    pub score: i32,
+    /// The last response could not be delivered to the player.
+    pub unreachable: bool,
+    /// Number of times the session was stored, checked by `SessionRepository::store`.
+    #[serde(default)]
+    pub version: u32,
+    /// Unix time in seconds of the last message from the player.
+    #[serde(default)]
+    pub last_activity: u64,
+    /// Earlier attempts to play the game, oldest first.
+    #[serde(default)]
+    pub history: Vec<PlayedAttempt>,
+    /// The score that counts for the game under its replay policy, set once
+    /// the current attempt is complete.
+    #[serde(default)]
+    pub final_score: Option<i32>,
 }
 
 impl GameSession {
@@ -41,33 +58,191 @@ impl GameSession {
             state: SessionState::New,
             results: Default::default(),
             score: 0,
+            unreachable: false,
+            version: 0,
+            last_activity: 0,
+            history: Default::default(),
+            final_score: None,
         }
     }
 
-    pub fn record(&mut self, topic_id: TopicId, score: u8) {
-        self.score += score as u16;
-        self.results.push(TopicResult { topic_id, score })
+    pub fn record(&mut self, topic_id: TopicId, score: i16) {
+        self.record_round(topic_id, score, 1)
+    }
+
+    /// Closes the topic after a round of `questions` questions that scored `score` in total.
+    pub fn record_round(&mut self, topic_id: TopicId, score: i16, questions: u8) {
+        self.score += score as i32;
+        self.results.push(TopicResult {
+            topic_id,
+            score,
+            questions,
+        })
     }
 
     pub fn has_played(&self, topic_id: TopicId) -> bool {
         self.results.iter().any(|r| r.topic_id == topic_id)
     }
+
+    /// Moves the current attempt to the history and starts the game over.
+    pub fn restart(&mut self) {
+        self.history.push(PlayedAttempt {
+            results: std::mem::take(&mut self.results),
+            score: self.score,
+            complete: self.state == SessionState::Complete,
+        });
+        self.score = 0;
+        self.final_score = None;
+        self.state = SessionState::New;
+    }
+
+    /// The score that counts for the game under `policy`.
+    pub fn counted_score(&self, policy: ReplayPolicy) -> i32 {
+        match policy {
+            ReplayPolicy::FirstScoreCounts => self
+                .history
+                .iter()
+                .find(|a| a.complete)
+                .map(|a| a.score)
+                .unwrap_or(self.score),
+            ReplayPolicy::Never | ReplayPolicy::Allowed => self.score,
+        }
+    }
+
+    /// Whether the player has started answering questions but not finished the game.
+    pub fn is_in_progress(&self) -> bool {
+        matches!(
+            self.state,
+            SessionState::Answering(_) | SessionState::ChoosingTopic
+        )
+    }
+}
+
+/// Current unix time in seconds.
+pub fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// Tells the engine what time it is.
+pub trait Clock: Send + Sync {
+    /// Unix time in milliseconds.
+    fn now_millis(&self) -> u64;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now_millis(&self) -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or_default()
+    }
 }
 
-#[derive(PartialEq, Clone, Default, Debug)]
+#[derive(Serialize, Deserialize, PartialEq, Clone, Default, Debug)]
+pub struct PlayedAttempt {
+    pub results: Vec<TopicResult>,
+    pub score: i32,
+    /// All topics were played, as opposed to the player quitting.
+    pub complete: bool,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Clone, Default, Debug)]
 pub struct TopicResult {
     pub topic_id: u8,
-    pub score: u8,
+    /// Points scored in the topic, negative when penalties outweigh the bonuses.
+    pub score: i16,
+    /// Number of questions asked in the round.
+    #[serde(default = "TopicResult::default_questions")]
+    pub questions: u8,
 }
 
-#[derive(PartialEq, Clone, Default, Debug)]
+impl TopicResult {
+    fn default_questions() -> u8 {
+        1
+    }
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Clone, Default, Debug)]
 pub struct AnswerAttempt {
     pub question_id: QuestionId,
     pub attempt: u8,
+    /// Orders the options of a multiple choice question, kept across retries.
+    #[serde(default)]
+    pub seed: u64,
+    /// Number of hints the player has seen.
+    #[serde(default)]
+    pub hints: u8,
+    /// Unix time in milliseconds when the question was asked.
+    #[serde(default)]
+    pub asked_at: u64,
+    /// Questions of the topic asked in this round, including the current one.
+    #[serde(default)]
+    pub asked: Vec<u8>,
+    /// Points of the earlier questions of the round.
+    #[serde(default)]
+    pub round_score: i16,
+    /// The player was told an answer is almost correct, which happens once per question.
+    #[serde(default)]
+    pub almost_correct: bool,
 }
 
-#[derive(PartialEq, Debug, Clone)]
+impl AnswerAttempt {
+    pub fn new(question_id: QuestionId, seed: u64, asked_at: u64) -> AnswerAttempt {
+        AnswerAttempt {
+            question_id,
+            attempt: 0,
+            seed,
+            hints: 0,
+            asked_at,
+            asked: vec![question_id.question()],
+            round_score: 0,
+            almost_correct: false,
+        }
+    }
+
+    /// The next question of the same round, after the current one scored `points`.
+    pub fn next_in_round(
+        &self,
+        question_id: QuestionId,
+        seed: u64,
+        asked_at: u64,
+        points: i16,
+    ) -> AnswerAttempt {
+        let mut asked = self.asked_in_round();
+        asked.push(question_id.question());
+        AnswerAttempt {
+            asked,
+            round_score: self.round_score.saturating_add(points),
+            ..AnswerAttempt::new(question_id, seed, asked_at)
+        }
+    }
+
+    /// Sessions stored before rounds existed only know the current question.
+    pub fn asked_in_round(&self) -> Vec<u8> {
+        if self.asked.is_empty() {
+            vec![self.question_id.question()]
+        } else {
+            self.asked.clone()
+        }
+    }
+
+    /// The same question with the same options, one attempt later.
+    pub fn retry(&self) -> AnswerAttempt {
+        AnswerAttempt {
+            attempt: self.attempt + 1,
+            ..self.clone()
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Default)]
 pub enum SessionState {
+    #[default]
     New,
     Deciding,
     Answering(AnswerAttempt),
@@ -81,16 +256,16 @@ impl SessionState {
         Answering(AnswerAttempt {
             question_id,
             attempt,
+            seed: 0,
+            hints: 0,
+            asked_at: 0,
+            asked: vec![question_id.question()],
+            round_score: 0,
+            almost_correct: false,
         })
     }
 }
 
-impl Default for SessionState {
-    fn default() -> Self {
-        New
-    }
-}
-
 #[derive(PartialEq, Debug, Clone)]
 pub struct PlayerPersonalInfo {
     pub id: PlayerId,
@@ -101,7 +276,36 @@ pub struct PlayerPersonalInfo {
 #[derive(PartialEq, Debug, Clone)]
 pub struct PlayerMessage {
     pub player_id: PlayerId,
-    pub text: String,
+    pub input: PlayerInput,
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub enum PlayerInput {
+    Text(String),
+    /// A quick reply or a postback button. `payload` is matched exactly, `text` is what the player saw.
+    Payload {
+        payload: String,
+        text: String,
+    },
+    /// The player opened the conversation through a link or an ad.
+    Referral,
+}
+
+impl PlayerInput {
+    pub fn text(&self) -> &str {
+        match self {
+            PlayerInput::Text(text) => text.as_str(),
+            PlayerInput::Payload { text, .. } => text.as_str(),
+            PlayerInput::Referral => "",
+        }
+    }
+
+    pub fn payload(&self) -> Option<&str> {
+        match self {
+            PlayerInput::Payload { payload, .. } => Some(payload.as_str()),
+            _ => None,
+        }
+    }
 }
 
 #[derive(PartialEq, Debug, Clone)]
@@ -112,12 +316,41 @@ pub enum ResponseMessage {
     AnswerQuestion(String),
     PleaseRetry,
     PleaseRetryLimits(u8),
-    Incorrect,
-    Correct(u16),
-    GameComplete(u16),
+    /// Points for the question, the total score and the correct answer.
+    Incorrect(i16, i32, Reveal),
+    /// Points for the question, the total score and, if the game reveals
+    /// answers to correct ones too, the explanation.
+    Correct(i16, i32, Option<Reveal>),
+    GameComplete(i32),
     ChooseNextTopic,
     AlreadyAnswered,
     Quit,
+    /// The answer is close to a correct one, probably misspelled.
+    AlmostCorrect,
+    /// A multiple choice question with its options in the order shown to the player.
+    AnswerChoice(String, Vec<String>),
+    /// A hint and the number of hints left for the question.
+    Hint(String, u8),
+    NoMoreHints,
+    /// The answer came after the time limit of the question. Holds the
+    /// points for the question, the total score and the correct answer.
+    TimeUp(i16, i32, Reveal),
+    /// The player gave up on the question.
+    Skipped(Reveal),
+    /// The current question and its options again.
+    RepeatQuestion(String, Vec<String>),
+    /// The total score and the topics left to play.
+    Score(i32, Vec<String>),
+    /// The rules again, with all topics of the game.
+    Help(Vec<String>),
+}
+
+/// The correct answer to a question and why it is correct.
+#[derive(PartialEq, Debug, Clone, Default)]
+pub struct Reveal {
+    pub answer: String,
+    /// Empty if the question has no explanation.
+    pub explanation: String,
 }
 
 impl ResponseMessage {
@@ -138,6 +371,9 @@ pub trait GameApplicationContext: Send + Sync {
     fn responder(&self) -> &dyn ResponseSender;
     fn sessions(&self) -> &dyn SessionRepository;
     fn definitions(&self) -> &dyn DefinitionsRepository;
+    fn clock(&self) -> &dyn Clock {
+        &SystemClock
+    }
 }
 
 pub trait ResponseTextFormatter: Send + Sync {
@@ -148,18 +384,72 @@ pub struct Response {
     pub to: PlayerId,
     pub channel: Arc<Channel>,
     pub message: ResponseMessage,
+    pub quick_replies: Vec<QuickReply>,
+    pub media: Option<Media>,
     pub format: Arc<dyn ResponseTextFormatter>,
 }
 
+#[derive(PartialEq, Debug, Clone)]
+pub struct QuickReply {
+    pub title: String,
+    pub payload: String,
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub enum RespondError {
+    /// The player can not receive messages, e.g. they blocked the page.
+    Unreachable(String),
+    /// The channel can not send messages, e.g. its token has expired.
+    ChannelUnavailable(String),
+    /// The response was not delivered for another reason, e.g. retries ran out.
+    Failed(String),
+}
+
+impl Display for RespondError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RespondError::Unreachable(reason) => write!(f, "player is unreachable: {}", reason),
+            RespondError::ChannelUnavailable(reason) => {
+                write!(f, "channel is unavailable: {}", reason)
+            }
+            RespondError::Failed(reason) => write!(f, "failed to respond: {}", reason),
+        }
+    }
+}
+
 #[async_trait]
 pub trait ResponseSender: Send + Sync {
-    async fn respond(&self, response: Response);
+    async fn respond(&self, response: Response) -> Result<(), RespondError>;
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub enum StoreError {
+    /// The session was stored by someone else since it was read. Holds the stored version.
+    Conflict(u32),
+    Failed(String),
+}
+
+impl Display for StoreError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StoreError::Conflict(version) => {
+                write!(
+                    f,
+                    "session was changed concurrently, now at version {}",
+                    version
+                )
+            }
+            StoreError::Failed(reason) => write!(f, "failed to store session: {}", reason),
+        }
+    }
 }
 
 #[async_trait]
 pub trait SessionRepository: Send + Sync {
     async fn get_by_id(&self, game_id: u32, player_id: &PlayerId) -> Option<GameSession>;
-    async fn store(&self, session: &GameSession);
+    /// Stores the session if the stored one still has the same `version`,
+    /// a missing session counts as version 0. The stored copy gets `version + 1`.
+    async fn store(&self, session: &GameSession) -> Result<(), StoreError>;
 }
 
 #[async_trait]
@@ -1,6 +1,10 @@
 pub mod admin;
+pub mod app;
+pub mod fake_graph_srv;
 pub mod fb_hook_srv;
 pub mod game_engine;
+mod mailbox;
 mod mock;
+mod seen_messages;
 pub mod services;
 mod text_util;
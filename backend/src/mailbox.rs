@@ -0,0 +1,204 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+use crate::fb_hook_srv::{InboundMessage, MessageHandler};
+use crate::game_engine::types::PlayerId;
+
+type Senders = Arc<Mutex<HashMap<PlayerId, UnboundedSender<InboundMessage>>>>;
+
+/// Delivers messages of a player one by one in the order they were posted.
+/// Every player with pending messages gets its own worker task, so different
+/// players are still processed concurrently. A worker exits as soon as its
+/// mailbox is empty.
+pub struct Mailboxes {
+    handler: Arc<dyn MessageHandler + Send + Sync>,
+    senders: Senders,
+}
+
+impl Mailboxes {
+    pub fn new(handler: Arc<dyn MessageHandler + Send + Sync>) -> Mailboxes {
+        Mailboxes {
+            handler,
+            senders: Default::default(),
+        }
+    }
+
+    pub fn deliver(&self, message: InboundMessage) {
+        let player_id = PlayerId {
+            channel_id: message.to.clone(),
+            id: message.from.clone(),
+        };
+        let mut senders = self.senders.lock().unwrap();
+        let message = match senders.get(&player_id) {
+            Some(sender) => match sender.send(message) {
+                Ok(_) => return,
+                Err(err) => err.0,
+            },
+            None => message,
+        };
+        let (sender, receiver) = unbounded_channel();
+        sender.send(message).unwrap();
+        senders.insert(player_id.clone(), sender);
+        tokio::spawn(run_worker(
+            player_id,
+            receiver,
+            self.handler.clone(),
+            self.senders.clone(),
+        ));
+    }
+}
+
+async fn run_worker(
+    player_id: PlayerId,
+    mut receiver: UnboundedReceiver<InboundMessage>,
+    handler: Arc<dyn MessageHandler + Send + Sync>,
+    senders: Senders,
+) {
+    loop {
+        // Messages are only sent while the lock is held, so nothing can be lost
+        // between finding the mailbox empty and removing it.
+        let message = {
+            let mut senders = senders.lock().unwrap();
+            match receiver.try_recv() {
+                Ok(message) => message,
+                Err(_) => {
+                    senders.remove(&player_id);
+                    return;
+                }
+            }
+        };
+        handler.process_message(message).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use async_trait::async_trait;
+    use hyper::{Body, Request, Response};
+    use tokio::sync::Notify;
+
+    use crate::fb_hook_srv::{InboundEvent, InboundMessage, MessageHandler};
+    use crate::mailbox::Mailboxes;
+
+    fn make_message(from: &str, text: &str) -> InboundMessage {
+        InboundMessage {
+            from: from.to_string(),
+            to: "1".to_string(),
+            event: InboundEvent::Text(text.to_string()),
+            ..Default::default()
+        }
+    }
+
+    /// Simulates the engine: reads the player state, yields, then writes it back.
+    #[derive(Default)]
+    struct CountingHandler {
+        counters: Mutex<HashMap<String, u32>>,
+        hist: Mutex<Vec<InboundMessage>>,
+    }
+
+    impl CountingHandler {
+        async fn wait_for(&self, num: usize) -> Vec<InboundMessage> {
+            for _ in 0..500 {
+                if self.hist.lock().unwrap().len() >= num {
+                    break;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            self.hist.lock().unwrap().clone()
+        }
+    }
+
+    #[async_trait]
+    impl MessageHandler for CountingHandler {
+        async fn process_message(&self, message: InboundMessage) {
+            let value = *self
+                .counters
+                .lock()
+                .unwrap()
+                .get(&message.from)
+                .unwrap_or(&0);
+            tokio::time::sleep(Duration::from_millis(1)).await;
+            self.counters
+                .lock()
+                .unwrap()
+                .insert(message.from.clone(), value + 1);
+            self.hist.lock().unwrap().push(message);
+        }
+
+        async fn process_other(&self, _: Request<Body>) -> Response<Body> {
+            Response::builder().status(404).body(Body::empty()).unwrap()
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn messages_of_a_player_are_processed_in_order_without_lost_updates() {
+        let handler = Arc::new(CountingHandler::default());
+        let mailboxes = Mailboxes::new(handler.clone());
+        let sent: Vec<InboundMessage> = (0..50)
+            .map(|i| make_message("a", i.to_string().as_str()))
+            .collect();
+        sent.iter().for_each(|msg| mailboxes.deliver(msg.clone()));
+
+        let received = handler.wait_for(sent.len()).await;
+        assert_eq!(sent, received);
+        assert_eq!(Some(&50), handler.counters.lock().unwrap().get("a"));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn mailbox_is_reopened_after_it_was_drained() {
+        let handler = Arc::new(CountingHandler::default());
+        let mailboxes = Mailboxes::new(handler.clone());
+        mailboxes.deliver(make_message("a", "1"));
+        handler.wait_for(1).await;
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        mailboxes.deliver(make_message("a", "2"));
+
+        assert_eq!(2, handler.wait_for(2).await.len());
+        assert_eq!(Some(&2), handler.counters.lock().unwrap().get("a"));
+    }
+
+    /// Blocks player "a" until a message of player "b" has been processed.
+    #[derive(Default)]
+    struct BlockingHandler {
+        released: Notify,
+        hist: Mutex<Vec<String>>,
+    }
+
+    #[async_trait]
+    impl MessageHandler for BlockingHandler {
+        async fn process_message(&self, message: InboundMessage) {
+            if message.from == "a" {
+                self.released.notified().await;
+            } else {
+                self.released.notify_one();
+            }
+            self.hist.lock().unwrap().push(message.from);
+        }
+
+        async fn process_other(&self, _: Request<Body>) -> Response<Body> {
+            Response::builder().status(404).body(Body::empty()).unwrap()
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn different_players_are_processed_concurrently() {
+        let handler = Arc::new(BlockingHandler::default());
+        let mailboxes = Mailboxes::new(handler.clone());
+        mailboxes.deliver(make_message("a", "1"));
+        mailboxes.deliver(make_message("b", "1"));
+
+        for _ in 0..500 {
+            if handler.hist.lock().unwrap().len() == 2 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(vec!["b", "a"], *handler.hist.lock().unwrap());
+    }
+}
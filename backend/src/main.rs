@@ -1,17 +1,14 @@
-use std::path::PathBuf;
-
-use async_trait::async_trait;
-use hyper::{Body, Request, Response};
-use quiz::fb_hook_srv::{FacebookHookServer, MessageHandler, TextMessage};
-use quiz::game_engine::engine::GameEngine;
-use quiz::game_engine::types::{
-    DefinitionsRepository, GameApplicationContext, PlayerId, PlayerMessage, ResponseSender,
-    SessionRepository,
-};
+use std::path::Path;
+use std::time::Duration;
+
+use quiz::app::{HandlerAdapter, WebApplicationContext};
+use quiz::fb_hook_srv::FacebookHookServer;
+use quiz::game_engine::types::SessionRepository;
 use quiz::services::definitions::FileRepository;
-use quiz::services::response::FbResponseService;
+use quiz::services::response::{FbResponseService, GraphApiSettings};
+use quiz::services::session_journal::{FileSessionRepository, FsyncPolicy};
 use quiz::services::sessions::InMemorySessionRepository;
-use std::sync::Arc;
+use quiz::services::sqlite_sessions::SqliteSessionRepository;
 
 const DATA_DIR: &str = "./deploy/data";
 
@@ -21,69 +18,26 @@ async fn main() {
     let ctx = create_context().await;
     let token = get_confirmation_token();
     log::info!("Using token {}", token);
-    let server = Box::leak(Box::new(FacebookHookServer::new_async(
-        token.as_str(),
-        HandlerAdapter::new(ctx),
-    )));
-    if let Err(err) = server.start(get_port()).await {
-        log::error!("Server failed to start {}", err)
-    }
-}
-
-struct HandlerAdapter {
-    engine: GameEngine,
-    ctx: &'static dyn GameApplicationContext,
-}
-
-impl HandlerAdapter {
-    pub fn new(ctx: &'static dyn GameApplicationContext) -> Arc<HandlerAdapter> {
-        Arc::new(HandlerAdapter {
-            engine: Default::default(),
-            ctx,
-        })
-    }
-}
-
-#[async_trait]
-impl MessageHandler for HandlerAdapter {
-    async fn process_text(&self, message: TextMessage) {
-        log::debug!("Processing {:?}", message);
-        self.engine
-            .process_message(
-                PlayerMessage {
-                    player_id: PlayerId {
-                        channel_id: message.to,
-                        id: message.from,
-                    },
-                    text: message.text,
-                },
-                self.ctx,
-            )
-            .await;
+    let app_secret = get_app_secret();
+    let dev_mode = is_dev_mode();
+    if app_secret.is_empty() && !dev_mode {
+        log::error!("APP_SECRET is not set, refusing to start outside of dev mode");
+        return;
     }
-
-    async fn process_other(&self, _request: Request<Body>) -> Response<Body> {
-        Response::builder().status(404).body(Body::empty()).unwrap()
-    }
-}
-
-struct WebApplicationContext {
-    responder: FbResponseService,
-    sessions: InMemorySessionRepository,
-    definitions: FileRepository,
-}
-
-impl GameApplicationContext for WebApplicationContext {
-    fn responder(&self) -> &dyn ResponseSender {
-        &self.responder
+    if dev_mode {
+        log::warn!("Dev mode is on, unsigned webhook events are accepted");
     }
-
-    fn sessions(&self) -> &dyn SessionRepository {
-        &self.sessions
-    }
-
-    fn definitions(&self) -> &dyn DefinitionsRepository {
-        &self.definitions
+    let server = Box::leak(Box::new(
+        FacebookHookServer::new_async(
+            token.as_str(),
+            app_secret.as_str(),
+            HandlerAdapter::new(ctx),
+        )
+        .with_dev_mode(dev_mode)
+        .with_max_body_size(get_max_body_size()),
+    ));
+    if let Err(err) = server.start(get_port()).await {
+        log::error!("Server failed to start {}", err)
     }
 }
 
@@ -91,13 +45,46 @@ async fn create_context() -> &'static WebApplicationContext {
     let path = std::env::current_dir()
         .unwrap()
         .join(get_data_dir().as_str());
-    Box::leak(Box::new(WebApplicationContext {
-        responder: FbResponseService::new(),
-        sessions: InMemorySessionRepository::default(),
-        definitions: FileRepository::load(&path)
+    Box::leak(Box::new(WebApplicationContext::new(
+        FbResponseService::with_settings(get_graph_api_settings()),
+        create_session_repository(&path),
+        FileRepository::load(&path)
             .await
             .expect("Failed to load definitions"),
-    }))
+    )))
+}
+
+fn create_session_repository(data_dir: &Path) -> Box<dyn SessionRepository> {
+    match get_session_store().as_str() {
+        "memory" => {
+            let repo = InMemorySessionRepository::default().with_ttl(get_session_ttl());
+            match get_max_sessions() {
+                Some(capacity) => Box::new(repo.with_capacity(capacity)),
+                None => Box::new(repo),
+            }
+        }
+        "file" => Box::new(
+            FileSessionRepository::load(data_dir)
+                .expect("Failed to load sessions")
+                .with_fsync(get_sessions_fsync()),
+        ),
+        "sqlite" => Box::new(
+            SqliteSessionRepository::open(&data_dir.join("sessions.db"))
+                .expect("Failed to open sessions database"),
+        ),
+        other => panic!("Unknown session store {}", other),
+    }
+}
+
+fn get_graph_api_settings() -> GraphApiSettings {
+    let defaults = GraphApiSettings::default();
+    GraphApiSettings {
+        base_url: std::env::var("GRAPH_API_URL").unwrap_or(defaults.base_url),
+        api_version: std::env::var("GRAPH_API_VERSION").unwrap_or(defaults.api_version),
+        allow_http: std::env::var("GRAPH_API_ALLOW_HTTP")
+            .map(|v| v == "1" || v == "true")
+            .unwrap_or(defaults.allow_http),
+    }
 }
 
 fn get_port() -> u16 {
@@ -111,6 +98,47 @@ fn get_confirmation_token() -> String {
     std::env::var("TOKEN").unwrap_or("MY_TEST_TOKEN".to_string())
 }
 
+fn get_max_body_size() -> usize {
+    std::env::var("MAX_BODY_SIZE")
+        .unwrap_or("1048576".to_string())
+        .parse()
+        .expect("Invalid max body size")
+}
+
+fn get_app_secret() -> String {
+    std::env::var("APP_SECRET").unwrap_or_default()
+}
+
+fn is_dev_mode() -> bool {
+    std::env::var("DEV_MODE")
+        .map(|v| v == "1" || v == "true")
+        .unwrap_or(false)
+}
+
 fn get_data_dir() -> String {
     std::env::var("DATA_DIR").unwrap_or(DATA_DIR.to_string())
 }
+
+fn get_session_store() -> String {
+    std::env::var("SESSION_STORE").unwrap_or("file".to_string())
+}
+
+fn get_sessions_fsync() -> FsyncPolicy {
+    std::env::var("SESSIONS_FSYNC")
+        .map(|v| FsyncPolicy::parse(v.as_str()).expect("Invalid sessions fsync policy"))
+        .unwrap_or(FsyncPolicy::Always)
+}
+
+fn get_session_ttl() -> Duration {
+    let secs = std::env::var("SESSION_TTL")
+        .unwrap_or("86400".to_string())
+        .parse()
+        .expect("Invalid session ttl");
+    Duration::from_secs(secs)
+}
+
+fn get_max_sessions() -> Option<usize> {
+    std::env::var("MAX_SESSIONS")
+        .ok()
+        .map(|v| v.parse().expect("Invalid max sessions"))
+}
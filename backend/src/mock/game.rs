@@ -4,15 +4,25 @@ use std::sync::Arc;
 use async_trait::async_trait;
 use atomic_refcell::AtomicRefCell;
 
-use crate::game_engine::game_def::Game;
+use crate::game_engine::game_def::{Game, Media};
 use crate::game_engine::types::{
-    Channel, ChannelId, DefinitionsRepository, GameApplicationContext, GameId, PlayerId, Response,
-    ResponseMessage, ResponseSender, ResponseTextFormatter, SessionRepository,
+    Channel, ChannelId, Clock, DefinitionsRepository, GameApplicationContext, GameId, GameSession,
+    PlayerId, QuickReply, RespondError, Response, ResponseMessage, ResponseSender,
+    SessionRepository, StoreError,
 };
 use crate::services::sessions::InMemorySessionRepository;
 
+/// Unix time in milliseconds the mock clock starts at.
+const START_TIME: u64 = 1_600_000_000_000;
+
 pub struct MockContext {
     messages: AtomicRefCell<Vec<ResponseMessage>>,
+    quick_replies: AtomicRefCell<Vec<Vec<QuickReply>>>,
+    media: AtomicRefCell<Vec<Option<Media>>>,
+    failure: AtomicRefCell<Option<RespondError>>,
+    conflicts: AtomicRefCell<u8>,
+    store_failure: AtomicRefCell<Option<StoreError>>,
+    now: AtomicRefCell<u64>,
     sessions: InMemorySessionRepository,
     game: Arc<Game>,
     channel: Arc<Channel>,
@@ -20,10 +30,20 @@ pub struct MockContext {
 
 impl MockContext {
     pub async fn new() -> Self {
+        Self::with_game("game-1.json").await
+    }
+
+    pub async fn with_game(file_name: &str) -> Self {
         MockContext {
             messages: Default::default(),
+            quick_replies: Default::default(),
+            media: Default::default(),
+            failure: Default::default(),
+            conflicts: Default::default(),
+            store_failure: Default::default(),
+            now: AtomicRefCell::new(START_TIME),
             sessions: Default::default(),
-            game: Arc::new(create_test_game().await),
+            game: Arc::new(create_test_game(file_name).await),
             channel: Arc::new(create_test_channel()),
         }
     }
@@ -33,12 +53,70 @@ impl MockContext {
     pub fn results(&self) -> Vec<ResponseMessage> {
         std::mem::take(self.messages.borrow_mut().deref_mut())
     }
+
+    /// Quick replies sent with every response, in the same order as `results`.
+    pub fn quick_replies(&self) -> Vec<Vec<QuickReply>> {
+        std::mem::take(self.quick_replies.borrow_mut().deref_mut())
+    }
+
+    /// Every following response is recorded and then reported as failed with `err`.
+    pub fn fail_responses(&self, err: RespondError) {
+        *self.failure.borrow_mut() = Some(err);
+    }
+
+    /// The next `times` stores fail as if another message changed the session first.
+    pub fn conflict_on_store(&self, times: u8) {
+        *self.conflicts.borrow_mut() = times;
+    }
+
+    /// Every following store fails with `err`.
+    pub fn fail_stores(&self, err: StoreError) {
+        *self.store_failure.borrow_mut() = Some(err);
+    }
+
+    pub fn advance_clock(&self, millis: u64) {
+        *self.now.borrow_mut() += millis;
+    }
+
+    /// Media sent with every response, in the same order as `results`.
+    pub fn media(&self) -> Vec<Option<Media>> {
+        std::mem::take(self.media.borrow_mut().deref_mut())
+    }
 }
 
 #[async_trait]
 impl ResponseSender for Arc<MockContext> {
-    async fn respond(&self, response: Response) {
-        self.messages.borrow_mut().push(response.message)
+    async fn respond(&self, response: Response) -> Result<(), RespondError> {
+        self.quick_replies.borrow_mut().push(response.quick_replies);
+        self.media.borrow_mut().push(response.media);
+        self.messages.borrow_mut().push(response.message);
+        match self.failure.borrow().clone() {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+}
+
+#[async_trait]
+impl SessionRepository for Arc<MockContext> {
+    async fn get_by_id(&self, game_id: GameId, player_id: &PlayerId) -> Option<GameSession> {
+        self.sessions.get_by_id(game_id, player_id).await
+    }
+
+    async fn store(&self, session: &GameSession) -> Result<(), StoreError> {
+        let conflict = {
+            let mut conflicts = self.conflicts.borrow_mut();
+            let conflict = *conflicts > 0;
+            *conflicts = conflicts.saturating_sub(1);
+            conflict
+        };
+        if conflict {
+            return Err(StoreError::Conflict(session.version + 1));
+        }
+        if let Some(err) = self.store_failure.borrow().clone() {
+            return Err(err);
+        }
+        self.sessions.store(session).await
     }
 }
 
@@ -48,7 +126,7 @@ impl DefinitionsRepository for Arc<MockContext> {
         Some(self.game.clone())
     }
 
-    async fn get_channel_by_id(&self, channel_id: &ChannelId) -> Option<Arc<Channel>> {
+    async fn get_channel_by_id(&self, _: &ChannelId) -> Option<Arc<Channel>> {
         Some(self.channel.clone())
     }
 }
@@ -58,21 +136,31 @@ impl GameApplicationContext for Arc<MockContext> {
         self
     }
     fn sessions(&self) -> &dyn SessionRepository {
-        &self.sessions
+        self
     }
 
     fn definitions(&self) -> &dyn DefinitionsRepository {
         self
     }
+
+    fn clock(&self) -> &dyn Clock {
+        self
+    }
+}
+
+impl Clock for Arc<MockContext> {
+    fn now_millis(&self) -> u64 {
+        *self.now.borrow()
+    }
 }
 
-async fn create_test_game() -> Game {
+async fn create_test_game(file_name: &str) -> Game {
     let file = std::env::current_dir()
         .unwrap()
         .join("src")
         .join("test_resources")
         .join("games")
-        .join("game-1.json");
+        .join(file_name);
     Game::load(&file).await.unwrap()
 }
 
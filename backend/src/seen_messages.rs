@@ -0,0 +1,96 @@
+use std::collections::{HashSet, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Remembers message ids for `ttl`, holding at most `capacity` of them.
+/// The oldest ids are forgotten first when the capacity is reached.
+pub struct SeenMessages {
+    ttl: Duration,
+    capacity: usize,
+    state: Mutex<SeenState>,
+}
+
+#[derive(Default)]
+struct SeenState {
+    ids: HashSet<String>,
+    order: VecDeque<(Instant, String)>,
+}
+
+impl SeenMessages {
+    pub fn new(capacity: usize, ttl: Duration) -> SeenMessages {
+        SeenMessages {
+            ttl,
+            capacity,
+            state: Default::default(),
+        }
+    }
+
+    /// Returns false if the id was already seen within the ttl.
+    pub fn insert(&self, id: &str) -> bool {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        state.expire(now, self.ttl);
+        if state.ids.contains(id) {
+            return false;
+        }
+        while state.order.len() >= self.capacity {
+            state.pop_oldest();
+        }
+        if self.capacity > 0 {
+            state.ids.insert(id.to_string());
+            state.order.push_back((now, id.to_string()));
+        }
+        true
+    }
+}
+
+impl SeenState {
+    fn expire(&mut self, now: Instant, ttl: Duration) {
+        while let Some((seen_at, _)) = self.order.front() {
+            if now.duration_since(*seen_at) < ttl {
+                break;
+            }
+            self.pop_oldest();
+        }
+    }
+
+    fn pop_oldest(&mut self) {
+        if let Some((_, id)) = self.order.pop_front() {
+            self.ids.remove(&id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::seen_messages::SeenMessages;
+
+    #[test]
+    fn repeated_id_is_reported_once() {
+        let seen = SeenMessages::new(10, Duration::from_secs(60));
+        assert!(seen.insert("m1"));
+        assert!(seen.insert("m2"));
+        assert!(!seen.insert("m1"));
+        assert!(!seen.insert("m2"));
+    }
+
+    #[test]
+    fn oldest_ids_are_forgotten_when_full() {
+        let seen = SeenMessages::new(2, Duration::from_secs(60));
+        assert!(seen.insert("m1"));
+        assert!(seen.insert("m2"));
+        assert!(seen.insert("m3"));
+        assert!(seen.insert("m1"));
+        assert!(!seen.insert("m3"));
+    }
+
+    #[test]
+    fn ids_expire_after_ttl() {
+        let seen = SeenMessages::new(10, Duration::from_millis(20));
+        assert!(seen.insert("m1"));
+        std::thread::sleep(Duration::from_millis(30));
+        assert!(seen.insert("m1"));
+    }
+}
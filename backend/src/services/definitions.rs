@@ -1,33 +1,50 @@
 use std::collections::HashMap;
-use std::path::PathBuf;
-use std::sync::{Arc, RwLock};
+use std::path::Path;
+use std::sync::Arc;
 
+use arc_swap::ArcSwap;
 use async_trait::async_trait;
 
 use crate::game_engine::game_def::Game;
 use crate::game_engine::types::{Channel, ChannelId, DefinitionsRepository, GameId};
 
+/// Games and channels loaded from the data dir. They are published as one
+/// immutable snapshot, so readers never wait and a reload replaces both at once.
 pub struct FileRepository {
-    games: RwLock<HashMap<GameId, Arc<Game>>>,
-    channels: RwLock<HashMap<ChannelId, Arc<Channel>>>,
-    data_dir: PathBuf,
+    snapshot: ArcSwap<Definitions>,
+}
+
+struct Definitions {
+    games: HashMap<GameId, Arc<Game>>,
+    channels: HashMap<ChannelId, Arc<Channel>>,
 }
 
 #[async_trait]
 impl DefinitionsRepository for FileRepository {
     async fn get_game_by_id(&self, game_id: GameId) -> Option<Arc<Game>> {
-        let l = self.games.read().unwrap();
-        l.get(&game_id).cloned()
+        self.snapshot.load().games.get(&game_id).cloned()
     }
 
     async fn get_channel_by_id(&self, channel_id: &ChannelId) -> Option<Arc<Channel>> {
-        let l = self.channels.read().unwrap();
-        l.get(channel_id).cloned()
+        self.snapshot.load().channels.get(channel_id).cloned()
     }
 }
 
 impl FileRepository {
-    pub async fn load(data_dir: &PathBuf) -> anyhow::Result<FileRepository> {
+    pub async fn load(data_dir: &Path) -> anyhow::Result<FileRepository> {
+        anyhow::Ok(FileRepository {
+            snapshot: ArcSwap::from_pointee(Self::load_definitions(data_dir).await?),
+        })
+    }
+
+    /// Loads the definitions again and publishes them if all of them are valid.
+    pub async fn reload(&self, data_dir: &Path) -> anyhow::Result<()> {
+        self.snapshot
+            .store(Arc::new(Self::load_definitions(data_dir).await?));
+        anyhow::Ok(())
+    }
+
+    async fn load_definitions(data_dir: &Path) -> anyhow::Result<Definitions> {
         let channels = Self::load_channels(data_dir).await?;
         let games = Self::load_games(data_dir).await?;
         log::info!(
@@ -35,14 +52,10 @@ impl FileRepository {
             channels.len(),
             games.len()
         );
-        anyhow::Ok(FileRepository {
-            games: RwLock::new(games),
-            channels: RwLock::new(channels),
-            data_dir: data_dir.clone(),
-        })
+        anyhow::Ok(Definitions { games, channels })
     }
 
-    async fn load_channels(data_dir: &PathBuf) -> anyhow::Result<HashMap<ChannelId, Arc<Channel>>> {
+    async fn load_channels(data_dir: &Path) -> anyhow::Result<HashMap<ChannelId, Arc<Channel>>> {
         let content = tokio::fs::read(data_dir.join("channels.json")).await?;
         let channels: Vec<Channel> = serde_json::from_slice(content.as_slice())?;
         anyhow::Ok(
@@ -53,7 +66,7 @@ impl FileRepository {
         )
     }
 
-    async fn load_games(data_dir: &PathBuf) -> anyhow::Result<HashMap<GameId, Arc<Game>>> {
+    async fn load_games(data_dir: &Path) -> anyhow::Result<HashMap<GameId, Arc<Game>>> {
         let mut list = tokio::fs::read_dir(data_dir).await?;
         let mut result: Vec<Game> = Default::default();
         while let Some(file) = list.next_entry().await? {
@@ -70,8 +83,9 @@ impl FileRepository {
 #[cfg(test)]
 mod tests {
     use std::path::PathBuf;
+    use std::sync::Arc;
 
-    use crate::game_engine::types::GameId;
+    use crate::game_engine::types::DefinitionsRepository;
     use crate::services::definitions::FileRepository;
 
     fn test_data_dir() -> PathBuf {
@@ -85,8 +99,9 @@ mod tests {
     #[tokio::test]
     async fn test_games_are_loaded_correctly_from_file() {
         let games = FileRepository::load_games(&test_data_dir()).await.unwrap();
-        assert_eq!(1, games.len());
-        assert_eq!("#TEST_GAME", games.get(&1).unwrap().name)
+        assert_eq!(2, games.len());
+        assert_eq!("#TEST_GAME", games.get(&1).unwrap().name);
+        assert_eq!("#TEST_GAME2", games.get(&2).unwrap().name)
     }
 
     #[tokio::test]
@@ -97,4 +112,17 @@ mod tests {
         assert_eq!(1, channels.len());
         assert_eq!("test channel", channels.get("#id1").unwrap().name)
     }
+
+    #[tokio::test]
+    async fn reload_publishes_new_definitions() {
+        let repo = FileRepository::load(&test_data_dir()).await.unwrap();
+        let before = repo.get_game_by_id(1).await.unwrap();
+        repo.reload(&test_data_dir()).await.unwrap();
+        let after = repo.get_game_by_id(1).await.unwrap();
+
+        assert!(!Arc::ptr_eq(&before, &after));
+        assert_eq!(before.name, after.name);
+        assert!(repo.reload(&test_data_dir().join("missing")).await.is_err());
+        assert!(Arc::ptr_eq(&after, &repo.get_game_by_id(1).await.unwrap()));
+    }
 }
@@ -0,0 +1,183 @@
+use std::fmt::{Display, Formatter};
+
+use serde_json::Value;
+
+use crate::game_engine::types::RespondError;
+
+const CODE_API_UNKNOWN: i64 = 1;
+const CODE_API_SERVICE: i64 = 2;
+const CODE_TOO_MANY_CALLS: i64 = 4;
+const CODE_USER_TOO_MANY_CALLS: i64 = 17;
+const CODE_PAGE_TOO_MANY_CALLS: i64 = 32;
+const CODE_INVALID_PARAMETER: i64 = 100;
+const CODE_ACCESS_TOKEN: i64 = 190;
+const CODE_USER_UNAVAILABLE: i64 = 551;
+const CODE_RATE_LIMIT: i64 = 613;
+const SUBCODE_NO_MATCHING_USER: i64 = 2018001;
+
+/// A failed call to the Graph API.
+#[derive(Debug, Clone, PartialEq)]
+pub enum GraphError {
+    /// The request did not get an HTTP response.
+    Transport(String),
+    /// The API responded with an error status.
+    Api {
+        status: u16,
+        code: i64,
+        subcode: Option<i64>,
+        message: String,
+    },
+}
+
+impl GraphError {
+    /// Parses the `{"error": {...}}` body the Graph API sends with error statuses.
+    pub fn from_response(status: u16, body: &[u8]) -> GraphError {
+        let root: Value = serde_json::from_slice(body).unwrap_or_default();
+        let error = &root["error"];
+        GraphError::Api {
+            status,
+            code: error["code"].as_i64().unwrap_or_default(),
+            subcode: error["error_subcode"].as_i64(),
+            message: error["message"]
+                .as_str()
+                .map(|m| m.to_string())
+                .unwrap_or_else(|| String::from_utf8_lossy(body).to_string()),
+        }
+    }
+
+    /// Whether sending the same request later may succeed.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            GraphError::Transport(_) => true,
+            GraphError::Api { status, code, .. } => {
+                *status >= 500
+                    || matches!(
+                        *code,
+                        CODE_API_UNKNOWN
+                            | CODE_API_SERVICE
+                            | CODE_TOO_MANY_CALLS
+                            | CODE_USER_TOO_MANY_CALLS
+                            | CODE_PAGE_TOO_MANY_CALLS
+                            | CODE_RATE_LIMIT
+                    )
+            }
+        }
+    }
+}
+
+impl Display for GraphError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GraphError::Transport(err) => write!(f, "transport error: {}", err),
+            GraphError::Api {
+                status,
+                code,
+                subcode,
+                message,
+            } => write!(
+                f,
+                "status {}, code {}, subcode {:?}: {}",
+                status, code, subcode, message
+            ),
+        }
+    }
+}
+
+impl From<GraphError> for RespondError {
+    fn from(err: GraphError) -> Self {
+        match &err {
+            GraphError::Api { code, .. } if *code == CODE_USER_UNAVAILABLE => {
+                RespondError::Unreachable(err.to_string())
+            }
+            GraphError::Api { code, subcode, .. }
+                if *code == CODE_INVALID_PARAMETER
+                    && *subcode == Some(SUBCODE_NO_MATCHING_USER) =>
+            {
+                RespondError::Unreachable(err.to_string())
+            }
+            GraphError::Api { code, .. } if *code == CODE_ACCESS_TOKEN => {
+                RespondError::ChannelUnavailable(err.to_string())
+            }
+            _ => RespondError::Failed(err.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::game_engine::types::RespondError;
+    use crate::services::graph_error::GraphError;
+
+    fn api_error(status: u16, code: i64, subcode: Option<i64>) -> GraphError {
+        let body = match subcode {
+            Some(subcode) => format!(
+                r#"{{"error": {{"message": "msg", "code": {}, "error_subcode": {}}}}}"#,
+                code, subcode
+            ),
+            None => format!(r#"{{"error": {{"message": "msg", "code": {}}}}}"#, code),
+        };
+        GraphError::from_response(status, body.as_bytes())
+    }
+
+    #[test]
+    fn error_body_is_parsed() {
+        let body = r#"{"error": {"message": "Invalid OAuth access token.", "type": "OAuthException", "code": 190, "error_subcode": 460, "fbtrace_id": "x"}}"#;
+        assert_eq!(
+            GraphError::Api {
+                status: 400,
+                code: 190,
+                subcode: Some(460),
+                message: "Invalid OAuth access token.".to_string(),
+            },
+            GraphError::from_response(400, body.as_bytes())
+        )
+    }
+
+    #[test]
+    fn unparsable_body_is_kept_as_message() {
+        assert_eq!(
+            GraphError::Api {
+                status: 502,
+                code: 0,
+                subcode: None,
+                message: "Bad Gateway".to_string(),
+            },
+            GraphError::from_response(502, b"Bad Gateway")
+        )
+    }
+
+    #[test]
+    fn rate_limits_and_server_errors_are_transient() {
+        assert!(api_error(400, 4, None).is_transient());
+        assert!(api_error(400, 613, None).is_transient());
+        assert!(api_error(500, 0, None).is_transient());
+        assert!(GraphError::Transport("reset".to_string()).is_transient());
+    }
+
+    #[test]
+    fn token_and_user_errors_are_permanent() {
+        assert!(!api_error(400, 190, None).is_transient());
+        assert!(!api_error(400, 551, None).is_transient());
+        assert!(!api_error(400, 100, None).is_transient());
+    }
+
+    #[test]
+    fn errors_are_classified_for_the_engine() {
+        assert!(matches!(
+            RespondError::from(api_error(400, 551, Some(1545041))),
+            RespondError::Unreachable(_)
+        ));
+        assert!(matches!(
+            RespondError::from(api_error(400, 100, Some(2018001))),
+            RespondError::Unreachable(_)
+        ));
+        assert!(matches!(
+            RespondError::from(api_error(400, 190, None)),
+            RespondError::ChannelUnavailable(_)
+        ));
+        assert!(matches!(
+            RespondError::from(api_error(400, 613, None)),
+            RespondError::Failed(_)
+        ));
+    }
+}
@@ -1,3 +1,6 @@
 pub mod definitions;
+pub mod graph_error;
 pub mod response;
+pub mod session_journal;
 pub mod sessions;
+pub mod sqlite_sessions;
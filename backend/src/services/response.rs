@@ -1,55 +1,216 @@
-use std::sync::Arc;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
 
 use async_trait::async_trait;
 use hyper::client::HttpConnector;
 use hyper::header::CONTENT_TYPE;
 use hyper::{Body, Client, Method, Request};
 use hyper_rustls::HttpsConnector;
+use rand::Rng;
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 
-use crate::game_engine::types::{
-    PlayerId, Response, ResponseMessage, ResponseSender, ResponseTextFormatter,
-};
+use crate::game_engine::game_def::Media;
+use crate::game_engine::types::{ChannelId, QuickReply, RespondError, Response, ResponseSender};
+use crate::services::graph_error::GraphError;
+
+const DEFAULT_MAX_RETRIES: u32 = 3;
+const DEFAULT_RETRY_DELAY: Duration = Duration::from_millis(250);
+
+/// Where the Send API lives. Point `base_url` to a local stub with `allow_http` for tests.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GraphApiSettings {
+    pub base_url: String,
+    pub api_version: String,
+    pub allow_http: bool,
+}
+
+impl Default for GraphApiSettings {
+    fn default() -> Self {
+        GraphApiSettings {
+            base_url: "https://graph.facebook.com".to_string(),
+            api_version: "v12.0".to_string(),
+            allow_http: false,
+        }
+    }
+}
+
+impl GraphApiSettings {
+    fn messages_url(&self, token: &str) -> String {
+        format!(
+            "{}/{}/me/messages?access_token={}",
+            self.base_url.trim_end_matches('/'),
+            self.api_version,
+            encode_query_value(token)
+        )
+    }
+}
+
+fn encode_query_value(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
 
 pub struct FbResponseService {
     client: Arc<Client<HttpsConnector<HttpConnector>, Body>>,
+    settings: GraphApiSettings,
+    /// Ids of media already uploaded with `is_reusable`, per channel.
+    attachments: RwLock<HashMap<(ChannelId, Media), String>>,
+    max_retries: u32,
+    retry_delay: Duration,
+}
+
+impl Default for FbResponseService {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl FbResponseService {
     pub fn new() -> Self {
-        let https = hyper_rustls::HttpsConnectorBuilder::new()
-            .with_native_roots()
-            .https_only()
-            .enable_http1()
-            .build();
+        Self::with_settings(Default::default())
+    }
+
+    pub fn with_settings(settings: GraphApiSettings) -> Self {
+        let builder = hyper_rustls::HttpsConnectorBuilder::new().with_native_roots();
+        let https = if settings.allow_http {
+            builder.https_or_http().enable_http1().build()
+        } else {
+            builder.https_only().enable_http1().build()
+        };
         FbResponseService {
             client: Arc::new(Client::builder().build(https)),
+            settings,
+            attachments: Default::default(),
+            max_retries: DEFAULT_MAX_RETRIES,
+            retry_delay: DEFAULT_RETRY_DELAY,
+        }
+    }
+
+    /// Transient failures are retried up to `max_retries` times. The delay before retry `n`
+    /// is `retry_delay * 2^n`, randomly shortened by up to a half.
+    pub fn with_retries(mut self, max_retries: u32, retry_delay: Duration) -> Self {
+        self.max_retries = max_retries;
+        self.retry_delay = retry_delay;
+        self
+    }
+
+    async fn send_media(&self, response: &Response, media: &Media) -> Result<(), GraphError> {
+        let key = (response.channel.channel_id.clone(), media.clone());
+        let cached = self.attachments.read().unwrap().get(&key).cloned();
+        let json = create_attachment_response(response.to.id.as_str(), media, cached.as_deref());
+        let reply = self
+            .send_message(response.channel.token.as_str(), json)
+            .await?;
+        if cached.is_none() {
+            if let Some(attachment_id) = reply["attachment_id"].as_str() {
+                self.attachments
+                    .write()
+                    .unwrap()
+                    .insert(key, attachment_id.to_string());
+            }
         }
+        Ok(())
     }
 
-    async fn send_message(&self, token: &str, json: String) {
+    async fn send_message(&self, token: &str, json: String) -> Result<Value, GraphError> {
+        let mut attempt = 0;
+        loop {
+            match self.try_send_message(token, json.clone()).await {
+                Err(err) if err.is_transient() && attempt < self.max_retries => {
+                    let delay = backoff_delay(self.retry_delay, attempt);
+                    log::warn!("Retrying in {:?} after {}", delay, err);
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    async fn try_send_message(&self, token: &str, json: String) -> Result<Value, GraphError> {
         let request = Request::builder()
             .method(Method::POST)
-            .uri(format!(
-                "https://graph.facebook.com/v12.0/me/messages?access_token={}",
-                token
-            ))
+            .uri(self.settings.messages_url(token))
             .header(CONTENT_TYPE, "application/json")
             .body(Body::from(json))
             .unwrap();
-        if let Err(err) = self.client.request(request).await {
-            log::error!("Failed to respond: {}", err)
+        let response = self
+            .client
+            .request(request)
+            .await
+            .map_err(|err| GraphError::Transport(err.to_string()))?;
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await;
+        if status.is_success() {
+            // The message is delivered, so a broken reply must not make it resent.
+            let reply = body
+                .map_err(anyhow::Error::from)
+                .and_then(|buf| anyhow::Ok(serde_json::from_slice(buf.as_ref())?));
+            Ok(reply.unwrap_or_else(|err| {
+                log::warn!("Failed to read the reply to a sent message {}", err);
+                Value::Null
+            }))
+        } else {
+            let buf = body.map_err(|err| GraphError::Transport(err.to_string()))?;
+            Err(GraphError::from_response(status.as_u16(), buf.as_ref()))
         }
     }
 }
 
-fn create_text_response(id: &str, text: &str) -> String {
+fn backoff_delay(retry_delay: Duration, attempt: u32) -> Duration {
+    let delay = retry_delay * 2u32.pow(attempt.min(16));
+    delay.mul_f64(rand::thread_rng().gen_range(0.5..=1.0))
+}
+
+fn create_attachment_response(id: &str, media: &Media, attachment_id: Option<&str>) -> String {
+    let payload = match attachment_id {
+        Some(attachment_id) => AttachmentPayload {
+            is_reusable: None,
+            url: None,
+            attachment_id: Some(attachment_id.to_string()),
+        },
+        None => AttachmentPayload {
+            is_reusable: Some(true),
+            url: Some(media.url.clone()),
+            attachment_id: None,
+        },
+    };
+    let msg = MessageWrapper {
+        messaging_type: "RESPONSE".to_string(),
+        recipient: MessageRecipient { id: id.to_string() },
+        message: MessageContent {
+            text: None,
+            attachment: Some(Attachment {
+                kind: media.kind.as_str().to_string(),
+                payload,
+            }),
+            quick_replies: None,
+        },
+    };
+    serde_json::to_string(&msg).unwrap()
+}
+
+fn create_text_response(id: &str, text: &str, quick_replies: &[QuickReply]) -> String {
     let msg = MessageWrapper {
         messaging_type: "RESPONSE".to_string(),
         recipient: MessageRecipient { id: id.to_string() },
         message: MessageContent {
             text: Some(text.to_string()),
             attachment: None,
+            quick_replies: if quick_replies.is_empty() {
+                None
+            } else {
+                Some(quick_replies.iter().map(MessageQuickReply::from).collect())
+            },
         },
     };
     serde_json::to_string(&msg).unwrap()
@@ -57,17 +218,25 @@ fn create_text_response(id: &str, text: &str) -> String {
 
 #[async_trait]
 impl ResponseSender for FbResponseService {
-    async fn respond(&self, response: Response) {
+    async fn respond(&self, response: Response) -> Result<(), RespondError> {
         log::debug!(
             "Responding to {} on {} with {:?}",
             response.to.id,
             response.to.channel_id,
             response.message
         );
+        if let Some(media) = &response.media {
+            self.send_media(&response, media).await?;
+        }
         let text = response.format.format(response.message);
-        let json = create_text_response(response.to.id.as_str(), text.as_str());
+        let json = create_text_response(
+            response.to.id.as_str(),
+            text.as_str(),
+            response.quick_replies.as_slice(),
+        );
         self.send_message(response.channel.token.as_str(), json)
-            .await;
+            .await?;
+        Ok(())
     }
 }
 
@@ -89,6 +258,25 @@ struct MessageContent {
     pub text: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub attachment: Option<Attachment>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub quick_replies: Option<Vec<MessageQuickReply>>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct MessageQuickReply {
+    pub content_type: String,
+    pub title: String,
+    pub payload: String,
+}
+
+impl From<&QuickReply> for MessageQuickReply {
+    fn from(reply: &QuickReply) -> Self {
+        MessageQuickReply {
+            content_type: "text".to_string(),
+            title: reply.title.clone(),
+            payload: reply.payload.clone(),
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize)]
@@ -100,6 +288,279 @@ struct Attachment {
 
 #[derive(Serialize, Deserialize)]
 struct AttachmentPayload {
-    pub is_reusable: bool,
-    pub url: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub is_reusable: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub attachment_id: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use serde_json::{json, Value};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use crate::fake_graph_srv::{FakeGraphServer, ScriptedError};
+    use crate::game_engine::game_def::{Game, Media, MediaKind};
+    use crate::game_engine::types::{
+        Channel, PlayerId, QuickReply, RespondError, Response, ResponseMessage, ResponseSender,
+    };
+    use crate::services::response::{
+        backoff_delay, create_attachment_response, create_text_response, FbResponseService,
+        GraphApiSettings,
+    };
+
+    async fn start_fake_graph() -> FakeGraphServer {
+        FakeGraphServer::start(SocketAddr::from(([127, 0, 0, 1], 0)))
+            .await
+            .unwrap()
+    }
+
+    fn fake_graph_service(server: &FakeGraphServer, max_retries: u32) -> FbResponseService {
+        FbResponseService::with_settings(GraphApiSettings {
+            base_url: format!("{}/", server.base_url()),
+            api_version: "v15.0".to_string(),
+            allow_http: true,
+        })
+        .with_retries(max_retries, Duration::from_millis(1))
+    }
+
+    fn make_response(message: ResponseMessage, media: Option<Media>) -> Response {
+        Response {
+            to: PlayerId {
+                channel_id: "1".to_string(),
+                id: "42".to_string(),
+            },
+            channel: Arc::new(Channel {
+                channel_id: "1".to_string(),
+                token: "token".to_string(),
+                ..Default::default()
+            }),
+            message,
+            quick_replies: Default::default(),
+            media,
+            format: Arc::new(Game::default()),
+        }
+    }
+
+    fn graph_error(status: u16, code: i64) -> ScriptedError {
+        ScriptedError {
+            recipient: None,
+            status,
+            code,
+            message: "error".to_string(),
+        }
+    }
+
+    fn test_media() -> Media {
+        Media {
+            kind: MediaKind::Image,
+            url: "https://example.com/q.png".to_string(),
+        }
+    }
+
+    #[test]
+    fn text_response_has_no_quick_replies_by_default() {
+        let json: Value =
+            serde_json::from_str(create_text_response("1", "hi", &[]).as_str()).unwrap();
+        assert_eq!(
+            json!({
+                "messaging_type": "RESPONSE",
+                "recipient": {"id": "1"},
+                "message": {"text": "hi"}
+            }),
+            json
+        )
+    }
+
+    #[test]
+    fn quick_replies_are_attached_to_text_response() {
+        let replies = vec![QuickReply {
+            title: "yes".to_string(),
+            payload: "YES".to_string(),
+        }];
+        let json: Value =
+            serde_json::from_str(create_text_response("1", "hi", &replies).as_str()).unwrap();
+        assert_eq!(
+            json!([{"content_type": "text", "title": "yes", "payload": "YES"}]),
+            json["message"]["quick_replies"]
+        )
+    }
+
+    #[test]
+    fn new_media_is_uploaded_as_reusable() {
+        let json: Value =
+            serde_json::from_str(create_attachment_response("1", &test_media(), None).as_str())
+                .unwrap();
+        assert_eq!(
+            json!({
+                "type": "image",
+                "payload": {"is_reusable": true, "url": "https://example.com/q.png"}
+            }),
+            json["message"]["attachment"]
+        )
+    }
+
+    #[test]
+    fn uploaded_media_is_sent_by_attachment_id() {
+        let json: Value = serde_json::from_str(
+            create_attachment_response("1", &test_media(), Some("1857777774821032")).as_str(),
+        )
+        .unwrap();
+        assert_eq!(
+            json!({
+                "type": "image",
+                "payload": {"attachment_id": "1857777774821032"}
+            }),
+            json["message"]["attachment"]
+        )
+    }
+
+    #[test]
+    fn access_token_is_encoded_in_url() {
+        assert_eq!(
+            "https://graph.facebook.com/v12.0/me/messages?access_token=%23a%2Bb",
+            GraphApiSettings::default().messages_url("#a+b")
+        )
+    }
+
+    #[test]
+    fn backoff_delay_grows_exponentially_with_jitter() {
+        let base = Duration::from_millis(100);
+        for attempt in 0..4 {
+            let delay = backoff_delay(base, attempt);
+            let max = base * 2u32.pow(attempt);
+            assert!(delay <= max, "{:?} > {:?}", delay, max);
+            assert!(delay >= max / 2, "{:?} < {:?}", delay, max / 2);
+        }
+    }
+
+    #[tokio::test]
+    async fn response_is_posted_to_configured_endpoint() {
+        let server = start_fake_graph().await;
+        let result = fake_graph_service(&server, 0)
+            .respond(make_response(ResponseMessage::Quit, None))
+            .await;
+
+        assert_eq!(Ok(()), result);
+        assert_eq!(vec!["Ok... Goodbye!"], server.texts("42"));
+    }
+
+    #[tokio::test]
+    async fn http_is_refused_unless_allowed() {
+        let server = start_fake_graph().await;
+        let service = FbResponseService::with_settings(GraphApiSettings {
+            base_url: server.base_url(),
+            ..Default::default()
+        })
+        .with_retries(0, Duration::from_millis(1));
+        let result = service
+            .respond(make_response(ResponseMessage::Quit, None))
+            .await;
+
+        assert!(result.is_err());
+        assert_eq!(0, server.request_count());
+    }
+
+    #[tokio::test]
+    async fn transient_failures_are_retried() {
+        let server = start_fake_graph().await;
+        server.fail_next(graph_error(500, 1));
+        server.fail_next(graph_error(400, 613));
+        let result = fake_graph_service(&server, 3)
+            .respond(make_response(ResponseMessage::Quit, None))
+            .await;
+
+        assert_eq!(Ok(()), result);
+        assert_eq!(3, server.request_count());
+        assert_eq!(1, server.messages("42").len());
+    }
+
+    #[tokio::test]
+    async fn retries_are_limited() {
+        let server = start_fake_graph().await;
+        (0..5).for_each(|_| server.fail_next(graph_error(400, 4)));
+        let result = fake_graph_service(&server, 2)
+            .respond(make_response(ResponseMessage::Quit, None))
+            .await;
+
+        assert!(matches!(result, Err(RespondError::Failed(_))));
+        assert_eq!(3, server.request_count());
+    }
+
+    #[tokio::test]
+    async fn message_is_not_resent_when_reply_is_cut_short() {
+        let listener = tokio::net::TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0)))
+            .await
+            .unwrap();
+        let addr = listener.local_addr().unwrap();
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                counter.fetch_add(1, Ordering::SeqCst);
+                let mut buf = [0; 4096];
+                let _ = socket.read(&mut buf).await;
+                let _ = socket
+                    .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 100\r\n\r\n{\"recip")
+                    .await;
+            }
+        });
+        let result = FbResponseService::with_settings(GraphApiSettings {
+            base_url: format!("http://{}/", addr),
+            api_version: "v15.0".to_string(),
+            allow_http: true,
+        })
+        .with_retries(3, Duration::from_millis(1))
+        .respond(make_response(ResponseMessage::Quit, None))
+        .await;
+
+        assert_eq!(Ok(()), result);
+        assert_eq!(1, requests.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn permanent_failures_are_reported_without_retries() {
+        let server = start_fake_graph().await;
+        server.fail_next(graph_error(400, 551));
+        let result = fake_graph_service(&server, 3)
+            .respond(make_response(ResponseMessage::Quit, None))
+            .await;
+
+        assert!(matches!(result, Err(RespondError::Unreachable(_))));
+        assert_eq!(1, server.request_count());
+    }
+
+    #[tokio::test]
+    async fn media_is_uploaded_once_per_channel() {
+        let server = start_fake_graph().await;
+        let service = fake_graph_service(&server, 0);
+        for _ in 0..2 {
+            service
+                .respond(make_response(ResponseMessage::Quit, Some(test_media())))
+                .await
+                .unwrap();
+        }
+
+        let attachments: Vec<Value> = server
+            .messages("42")
+            .into_iter()
+            .map(|m| m["attachment"]["payload"].clone())
+            .collect();
+        assert_eq!(
+            vec![
+                json!({"is_reusable": true, "url": "https://example.com/q.png"}),
+                Value::Null,
+                json!({"attachment_id": "a_1"}),
+                Value::Null,
+            ],
+            attachments
+        );
+    }
 }
@@ -0,0 +1,478 @@
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+
+use async_trait::async_trait;
+
+use crate::game_engine::types::{GameId, GameSession, PlayerId, SessionRepository, StoreError};
+use crate::services::sessions::{check_version, next_version, shard_index, DEFAULT_SHARDS};
+
+/// Journal of the sessions before they were sharded, read on startup.
+const LEGACY_JOURNAL_FILE: &str = "sessions.journal";
+const JOURNAL_PREFIX: &str = "sessions-";
+const JOURNAL_EXTENSION: &str = "journal";
+const DEFAULT_COMPACTION_THRESHOLD: usize = 10_000;
+
+/// When the journal is flushed to disk.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FsyncPolicy {
+    /// After every stored session.
+    Always,
+    /// After the given number of stored sessions.
+    EveryWrites(u32),
+    /// Whenever the OS decides to.
+    Never,
+}
+
+impl FsyncPolicy {
+    /// Parses `always`, `never` or a number of writes.
+    pub fn parse(value: &str) -> Option<FsyncPolicy> {
+        match value {
+            "always" => Some(FsyncPolicy::Always),
+            "never" => Some(FsyncPolicy::Never),
+            num => num.parse().ok().map(FsyncPolicy::EveryWrites),
+        }
+    }
+}
+
+type Sessions = HashMap<GameId, HashMap<PlayerId, GameSession>>;
+
+/// Keeps sessions in memory and appends every stored session to a journal
+/// in the data dir. The journal is replayed on startup and rewritten with
+/// only the latest session of every player once it grows past the
+/// compaction threshold. Journal writes run on the blocking thread pool.
+///
+/// Sessions are spread over shards by a hash of the player id, each with its
+/// own journal file and locks, so writes of players in different shards run
+/// in parallel.
+pub struct FileSessionRepository {
+    shards: Vec<Arc<JournalStore>>,
+    fsync: FsyncPolicy,
+    compaction_threshold: usize,
+}
+
+struct JournalStore {
+    sessions: RwLock<Sessions>,
+    journal: Mutex<Journal>,
+}
+
+struct Journal {
+    path: PathBuf,
+    writer: BufWriter<File>,
+    entries: usize,
+    unsynced: u32,
+}
+
+impl FileSessionRepository {
+    /// Replays the journals in `data_dir` and compacts them into the default number of shards.
+    pub fn load(data_dir: &Path) -> anyhow::Result<FileSessionRepository> {
+        FileSessionRepository::load_with_shards(data_dir, DEFAULT_SHARDS)
+    }
+
+    /// Replays the journals in `data_dir`, written with any number of shards,
+    /// and compacts them into `num` shards.
+    pub fn load_with_shards(data_dir: &Path, num: usize) -> anyhow::Result<FileSessionRepository> {
+        let num = num.max(1);
+        let files = journal_files(data_dir)?;
+        let mut shards: Vec<Sessions> = (0..num).map(|_| Sessions::new()).collect();
+        for path in files.iter() {
+            for session in read_journal(path)?
+                .into_values()
+                .flat_map(|s| s.into_values())
+            {
+                let sessions = shards[shard_index(&session.player_id, num)]
+                    .entry(session.game_id)
+                    .or_default();
+                if sessions
+                    .get(&session.player_id)
+                    .is_none_or(|stored| stored.version < session.version)
+                {
+                    sessions.insert(session.player_id.clone(), session);
+                }
+            }
+        }
+        let shards = shards
+            .into_iter()
+            .enumerate()
+            .map(|(shard, sessions)| {
+                let journal = Journal::compact(&data_dir.join(journal_file(shard)), &sessions)?;
+                anyhow::Ok(Arc::new(JournalStore {
+                    sessions: RwLock::new(sessions),
+                    journal: Mutex::new(journal),
+                }))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        for path in files.iter() {
+            if !(0..num).any(|shard| *path == data_dir.join(journal_file(shard))) {
+                std::fs::remove_file(path)?;
+            }
+        }
+        log::info!(
+            "Loaded {} sessions from {} into {} journals",
+            shards
+                .iter()
+                .map(|shard| shard.journal.lock().unwrap().entries)
+                .sum::<usize>(),
+            data_dir.display(),
+            num
+        );
+        anyhow::Ok(FileSessionRepository {
+            shards,
+            fsync: FsyncPolicy::Always,
+            compaction_threshold: DEFAULT_COMPACTION_THRESHOLD,
+        })
+    }
+
+    pub fn with_fsync(mut self, fsync: FsyncPolicy) -> Self {
+        self.fsync = fsync;
+        self
+    }
+
+    /// The journal is compacted when it has more than `threshold` entries
+    /// and at least twice as many entries as there are sessions.
+    pub fn with_compaction_threshold(mut self, threshold: usize) -> Self {
+        self.compaction_threshold = threshold;
+        self
+    }
+
+    fn shard(&self, player_id: &PlayerId) -> &Arc<JournalStore> {
+        &self.shards[shard_index(player_id, self.shards.len())]
+    }
+}
+
+#[async_trait]
+impl SessionRepository for FileSessionRepository {
+    async fn get_by_id(&self, game_id: GameId, player_id: &PlayerId) -> Option<GameSession> {
+        let l = self.shard(player_id).sessions.read().unwrap();
+        l.get(&game_id)
+            .and_then(|sessions| sessions.get(player_id).cloned())
+    }
+
+    async fn store(&self, session: &GameSession) -> Result<(), StoreError> {
+        let store = self.shard(&session.player_id).clone();
+        let session = session.clone();
+        let (fsync, compaction_threshold) = (self.fsync, self.compaction_threshold);
+        tokio::task::spawn_blocking(move || store.store(&session, fsync, compaction_threshold))
+            .await
+            .unwrap_or_else(|err| Err(StoreError::Failed(err.to_string())))
+    }
+}
+
+impl JournalStore {
+    /// Checks the version and appends the session to the journal, blocking on the file.
+    fn store(
+        &self,
+        session: &GameSession,
+        fsync: FsyncPolicy,
+        compaction_threshold: usize,
+    ) -> Result<(), StoreError> {
+        let mut journal = self.journal.lock().unwrap();
+        {
+            let sessions = self.sessions.read().unwrap();
+            let stored = sessions
+                .get(&session.game_id)
+                .and_then(|s| s.get(&session.player_id));
+            check_version(stored, session)?;
+        }
+        let session = next_version(session);
+        journal
+            .append(&session, fsync)
+            .map_err(|err| StoreError::Failed(err.to_string()))?;
+        self.sessions
+            .write()
+            .unwrap()
+            .entry(session.game_id)
+            .or_default()
+            .insert(session.player_id.clone(), session);
+        if let Err(err) = self.compact_if_needed(&mut journal, compaction_threshold) {
+            log::error!("Failed to compact session journal {}", err);
+        }
+        Ok(())
+    }
+
+    fn compact_if_needed(&self, journal: &mut Journal, threshold: usize) -> anyhow::Result<()> {
+        let sessions = self.sessions.read().unwrap();
+        let live: usize = sessions.values().map(|s| s.len()).sum();
+        if journal.entries > threshold && journal.entries >= live * 2 {
+            log::info!("Compacting {} journal entries", journal.entries);
+            *journal = Journal::compact(&journal.path, &sessions)?;
+        }
+        anyhow::Ok(())
+    }
+}
+
+impl Journal {
+    fn append(&mut self, session: &GameSession, fsync: FsyncPolicy) -> anyhow::Result<()> {
+        serde_json::to_writer(&mut self.writer, session)?;
+        self.writer.write_all(b"\n")?;
+        self.writer.flush()?;
+        self.entries += 1;
+        self.unsynced += 1;
+        let sync = match fsync {
+            FsyncPolicy::Always => true,
+            FsyncPolicy::EveryWrites(num) => self.unsynced >= num,
+            FsyncPolicy::Never => false,
+        };
+        if sync {
+            self.writer.get_ref().sync_data()?;
+            self.unsynced = 0;
+        }
+        anyhow::Ok(())
+    }
+
+    /// Writes the sessions into a new journal which replaces the one at `path`.
+    fn compact(path: &Path, sessions: &Sessions) -> anyhow::Result<Journal> {
+        let tmp_path = path.with_extension(format!("{}.tmp", JOURNAL_EXTENSION));
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        let mut entries = 0;
+        for session in sessions.values().flat_map(|s| s.values()) {
+            serde_json::to_writer(&mut writer, session)?;
+            writer.write_all(b"\n")?;
+            entries += 1;
+        }
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+        std::fs::rename(&tmp_path, path)?;
+        if let Some(dir) = path.parent() {
+            File::open(dir)?.sync_all()?;
+        }
+        let file = OpenOptions::new().append(true).open(path)?;
+        anyhow::Ok(Journal {
+            path: path.to_path_buf(),
+            writer: BufWriter::new(file),
+            entries,
+            unsynced: 0,
+        })
+    }
+}
+
+fn journal_file(shard: usize) -> String {
+    format!("{}{}.{}", JOURNAL_PREFIX, shard, JOURNAL_EXTENSION)
+}
+
+/// The journals in `dir`, sharded and legacy ones, whatever their number of shards.
+fn journal_files(dir: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let mut files = vec![];
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        let is_journal = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .is_some_and(|name| {
+                name == LEGACY_JOURNAL_FILE
+                    || (name.starts_with(JOURNAL_PREFIX)
+                        && name.ends_with(format!(".{}", JOURNAL_EXTENSION).as_str()))
+            });
+        if is_journal {
+            files.push(path);
+        }
+    }
+    files.sort();
+    anyhow::Ok(files)
+}
+
+/// Reads the latest session of every player. A torn last line, left by a
+/// crash in the middle of a write, is skipped.
+fn read_journal(path: &Path) -> anyhow::Result<Sessions> {
+    let mut sessions = Sessions::new();
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return anyhow::Ok(sessions),
+        Err(err) => return Err(err.into()),
+    };
+    let mut lines = BufReader::new(file).lines().peekable();
+    while let Some(line) = lines.next() {
+        let line = line?;
+        match serde_json::from_str::<GameSession>(line.as_str()) {
+            Ok(session) => {
+                sessions
+                    .entry(session.game_id)
+                    .or_default()
+                    .insert(session.player_id.clone(), session);
+            }
+            Err(err) if lines.peek().is_none() => {
+                log::warn!("Skipping incomplete last journal entry {}", err)
+            }
+            Err(err) => return Err(err.into()),
+        }
+    }
+    anyhow::Ok(sessions)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::path::{Path, PathBuf};
+
+    use crate::game_engine::game_def::QuestionId;
+    use crate::game_engine::types::{
+        GameSession, PlayerId, SessionRepository, SessionState, StoreError, TopicResult,
+    };
+    use crate::services::session_journal::{
+        journal_files, FileSessionRepository, FsyncPolicy, LEGACY_JOURNAL_FILE,
+    };
+
+    fn test_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("quiz-journal-{}", rand::random::<u64>()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn make_session(id: &str, score: i32) -> GameSession {
+        let mut session = GameSession::new(
+            &PlayerId {
+                channel_id: "1".to_string(),
+                id: id.to_string(),
+            },
+            1,
+        );
+        session.score = score;
+        session
+    }
+
+    fn lines(path: &Path) -> usize {
+        std::fs::read_to_string(path).unwrap().lines().count()
+    }
+
+    fn journal_lines(dir: &Path) -> usize {
+        journal_files(dir).unwrap().iter().map(|p| lines(p)).sum()
+    }
+
+    #[tokio::test]
+    async fn sessions_survive_restart() {
+        let dir = test_dir();
+        let mut session = make_session("a", 0);
+        session.state = SessionState::answering(QuestionId::default(), 1);
+        session.results.push(TopicResult {
+            topic_id: 1,
+            score: 2,
+            questions: 1,
+        });
+        session.version = 1;
+        {
+            let repo = FileSessionRepository::load(&dir).unwrap();
+            repo.store(&make_session("a", 1)).await.unwrap();
+            repo.store(&session).await.unwrap();
+            repo.store(&make_session("b", 3)).await.unwrap();
+        }
+
+        let repo = FileSessionRepository::load(&dir).unwrap();
+        session.version = 2;
+        assert_eq!(
+            Some(session.clone()),
+            repo.get_by_id(1, &session.player_id).await
+        );
+        assert_eq!(
+            Some(3),
+            repo.get_by_id(1, &make_session("b", 0).player_id)
+                .await
+                .map(|s| s.score)
+        );
+        assert_eq!(2, journal_lines(&dir));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn torn_last_entry_is_skipped() {
+        let dir = test_dir();
+        {
+            let repo = FileSessionRepository::load(&dir)
+                .unwrap()
+                .with_fsync(FsyncPolicy::Never);
+            repo.store(&make_session("a", 1)).await.unwrap();
+        }
+        let journal = journal_files(&dir)
+            .unwrap()
+            .into_iter()
+            .find(|path| lines(path) > 0)
+            .unwrap();
+        let mut file = std::fs::OpenOptions::new()
+            .append(true)
+            .open(journal)
+            .unwrap();
+        file.write_all(br#"{"player_id":{"channel_id":"1","#)
+            .unwrap();
+
+        let repo = FileSessionRepository::load(&dir).unwrap();
+        repo.store(&make_session("b", 2)).await.unwrap();
+        let repo = FileSessionRepository::load(&dir).unwrap();
+        let player = make_session("a", 0).player_id;
+        assert_eq!(Some(1), repo.get_by_id(1, &player).await.map(|s| s.score));
+        assert_eq!(2, journal_lines(&dir));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn journal_is_compacted_when_it_grows() {
+        let dir = test_dir();
+        let repo = FileSessionRepository::load(&dir)
+            .unwrap()
+            .with_fsync(FsyncPolicy::EveryWrites(10))
+            .with_compaction_threshold(5);
+        for score in 0..6 {
+            let mut session = make_session("a", score);
+            session.version = score as u32;
+            repo.store(&session).await.unwrap();
+        }
+        assert_eq!(1, journal_lines(&dir));
+
+        repo.store(&make_session("b", 1)).await.unwrap();
+        assert_eq!(2, journal_lines(&dir));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn stale_session_is_not_journaled() {
+        let dir = test_dir();
+        let repo = FileSessionRepository::load(&dir).unwrap();
+        repo.store(&make_session("a", 1)).await.unwrap();
+
+        assert_eq!(
+            Err(StoreError::Conflict(1)),
+            repo.store(&make_session("a", 2)).await
+        );
+        assert_eq!(1, journal_lines(&dir));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn journals_are_resharded_on_load() {
+        let dir = test_dir();
+        let mut legacy = make_session("a", 1);
+        legacy.version = 1;
+        std::fs::write(
+            dir.join(LEGACY_JOURNAL_FILE),
+            format!("{}\n", serde_json::to_string(&legacy).unwrap()),
+        )
+        .unwrap();
+        {
+            let repo = FileSessionRepository::load_with_shards(&dir, 8).unwrap();
+            for id in ["b", "c", "d"] {
+                repo.store(&make_session(id, 2)).await.unwrap();
+            }
+        }
+        assert_eq!(8, journal_files(&dir).unwrap().len());
+
+        let repo = FileSessionRepository::load_with_shards(&dir, 2).unwrap();
+        for (id, score) in [("a", 1), ("b", 2), ("c", 2), ("d", 2)] {
+            let player = make_session(id, 0).player_id;
+            assert_eq!(
+                Some(score),
+                repo.get_by_id(1, &player).await.map(|s| s.score)
+            );
+        }
+        assert_eq!(2, journal_files(&dir).unwrap().len());
+        assert_eq!(4, journal_lines(&dir));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn fsync_policy_is_parsed() {
+        assert_eq!(Some(FsyncPolicy::Always), FsyncPolicy::parse("always"));
+        assert_eq!(Some(FsyncPolicy::Never), FsyncPolicy::parse("never"));
+        assert_eq!(Some(FsyncPolicy::EveryWrites(8)), FsyncPolicy::parse("8"));
+        assert_eq!(None, FsyncPolicy::parse("sometimes"));
+    }
+}
@@ -1,30 +1,404 @@
-use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use std::sync::RwLock;
+use std::time::Duration;
 
 use async_trait::async_trait;
 
-use crate::game_engine::types::{GameId, GameSession, PlayerId, SessionRepository};
+use crate::game_engine::types::{
+    unix_time, GameId, GameSession, PlayerId, SessionRepository, SessionState, StoreError,
+};
 
-#[derive(Default)]
+pub(crate) const DEFAULT_SHARDS: usize = 16;
+
+type SessionKey = (GameId, PlayerId);
+
+/// Keeps sessions in memory. Sessions of players who have not started
+/// playing are evicted once idle for longer than the ttl, and the least
+/// recently stored sessions are evicted when there are more than `capacity`.
+///
+/// Sessions are spread over shards by a hash of the player id, each with its
+/// own lock, so players in different shards never wait for each other. The
+/// capacity applies to all shards together, and sessions of players who are
+/// not in the middle of a game are evicted before the ones who are.
 pub struct InMemorySessionRepository {
-    store: RwLock<HashMap<u32, HashMap<PlayerId, GameSession>>>,
+    shards: Vec<RwLock<SessionStore>>,
+    ttl: Option<Duration>,
+    capacity: Option<usize>,
+    /// Number of sessions in all shards.
+    count: AtomicUsize,
+    /// Orders the stores in all shards, so the least recently stored session can be found.
+    tick: AtomicU64,
+}
+
+#[derive(Default)]
+struct SessionStore {
+    sessions: HashMap<SessionKey, (GameSession, u64)>,
+    /// Keys of the sessions by the tick they were last stored at, oldest first.
+    lru: BTreeMap<u64, SessionKey>,
+    next_sweep: u64,
+}
+
+impl Default for InMemorySessionRepository {
+    fn default() -> Self {
+        InMemorySessionRepository {
+            shards: create_shards(DEFAULT_SHARDS),
+            ttl: None,
+            capacity: None,
+            count: Default::default(),
+            tick: Default::default(),
+        }
+    }
+}
+
+impl InMemorySessionRepository {
+    /// Replaces the shards with `num` empty ones.
+    pub fn with_shards(mut self, num: usize) -> Self {
+        self.shards = create_shards(num.max(1));
+        self.count = Default::default();
+        self
+    }
+
+    /// Idle `New`, `Deciding` and `Terminated` sessions are evicted after `ttl`.
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = Some(ttl);
+        self
+    }
+
+    /// At most `capacity` sessions are kept, the least recently stored are evicted first.
+    pub fn with_capacity(mut self, capacity: usize) -> Self {
+        self.capacity = Some(capacity);
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.count.load(Ordering::SeqCst)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Evicts the sessions idle for longer than the ttl at unix time `now`.
+    pub fn evict_expired(&self, now: u64) {
+        if let Some(ttl) = self.ttl {
+            for shard in self.shards.iter() {
+                let evicted = shard.write().unwrap().evict_expired(now, ttl);
+                self.count.fetch_sub(evicted, Ordering::SeqCst);
+            }
+        }
+    }
+
+    fn shard(&self, player_id: &PlayerId) -> &RwLock<SessionStore> {
+        &self.shards[shard_index(player_id, self.shards.len())]
+    }
+
+    /// Evicts the least recently stored session other than `except`, preferring
+    /// the ones not in progress. Locks one shard at a time.
+    fn evict_one(&self, except: &SessionKey) -> bool {
+        for in_progress in [false, true] {
+            let oldest = self
+                .shards
+                .iter()
+                .enumerate()
+                .filter_map(|(num, shard)| {
+                    let (tick, key) = shard.read().unwrap().oldest(in_progress, except)?;
+                    Some((tick, num, key))
+                })
+                .min_by_key(|(tick, _, _)| *tick);
+            if let Some((_, num, key)) = oldest {
+                if let Some(session) = self.shards[num].write().unwrap().remove(&key) {
+                    self.count.fetch_sub(1, Ordering::SeqCst);
+                    log_eviction(&session);
+                }
+                return true;
+            }
+        }
+        false
+    }
+}
+
+/// The shard of `num` that keeps sessions of the player.
+pub(crate) fn shard_index(player_id: &PlayerId, num: usize) -> usize {
+    let mut hasher = DefaultHasher::new();
+    player_id.hash(&mut hasher);
+    hasher.finish() as usize % num
+}
+
+fn create_shards(num: usize) -> Vec<RwLock<SessionStore>> {
+    (0..num).map(|_| Default::default()).collect()
+}
+
+fn log_eviction(session: &GameSession) {
+    if session.is_in_progress() {
+        log::warn!(
+            "Session store is full, evicting in-progress session of {:?} in game {}",
+            session.player_id,
+            session.game_id
+        );
+    } else {
+        log::debug!(
+            "Session store is full, evicting session of {:?}",
+            session.player_id
+        );
+    }
 }
 
 #[async_trait]
 impl SessionRepository for InMemorySessionRepository {
     async fn get_by_id(&self, game_id: GameId, player_id: &PlayerId) -> Option<GameSession> {
-        let l = self.store.read().unwrap();
-        l.get(&game_id)
-            .and_then(|sessions| sessions.get(player_id).cloned())
+        let l = self.shard(player_id).read().unwrap();
+        l.sessions
+            .get(&(game_id, player_id.clone()))
+            .map(|(session, _)| session.clone())
+    }
+
+    async fn store(&self, session: &GameSession) -> Result<(), StoreError> {
+        let key = (session.game_id, session.player_id.clone());
+        {
+            let mut l = self.shard(&session.player_id).write().unwrap();
+            check_version(l.sessions.get(&key).map(|(s, _)| s), session)?;
+            let tick = self.tick.fetch_add(1, Ordering::SeqCst);
+            if l.insert(key.clone(), next_version(session), tick) {
+                self.count.fetch_add(1, Ordering::SeqCst);
+            }
+            if let Some(ttl) = self.ttl {
+                let now = unix_time();
+                if now >= l.next_sweep {
+                    let evicted = l.evict_expired(now, ttl);
+                    self.count.fetch_sub(evicted, Ordering::SeqCst);
+                    l.next_sweep = now + (ttl.as_secs() / 10).max(1);
+                }
+            }
+        }
+        if let Some(capacity) = self.capacity {
+            while self.len() > capacity && self.evict_one(&key) {}
+        }
+        Ok(())
     }
+}
+
+impl SessionStore {
+    /// Whether the session was not stored before.
+    fn insert(&mut self, key: SessionKey, session: GameSession, tick: u64) -> bool {
+        let previous = self.sessions.insert(key.clone(), (session, tick));
+        if let Some((_, previous_tick)) = &previous {
+            self.lru.remove(previous_tick);
+        }
+        self.lru.insert(tick, key);
+        previous.is_none()
+    }
+
+    fn remove(&mut self, key: &SessionKey) -> Option<GameSession> {
+        let (session, tick) = self.sessions.remove(key)?;
+        self.lru.remove(&tick);
+        Some(session)
+    }
+
+    /// Returns the number of evicted sessions.
+    fn evict_expired(&mut self, now: u64, ttl: Duration) -> usize {
+        let cutoff = now.saturating_sub(ttl.as_secs());
+        let expired: Vec<SessionKey> = self
+            .sessions
+            .iter()
+            .filter(|(_, (session, _))| {
+                session.last_activity < cutoff
+                    && matches!(
+                        session.state,
+                        SessionState::New | SessionState::Deciding | SessionState::Terminated
+                    )
+            })
+            .map(|(key, _)| key.clone())
+            .collect();
+        if !expired.is_empty() {
+            log::debug!("Evicting {} idle sessions", expired.len());
+        }
+        expired.iter().for_each(|key| {
+            self.remove(key);
+        });
+        expired.len()
+    }
+
+    /// The least recently stored session other than `except` that is or is not in progress.
+    fn oldest(&self, in_progress: bool, except: &SessionKey) -> Option<(u64, SessionKey)> {
+        self.lru
+            .iter()
+            .find(|(_, key)| {
+                *key != except && self.sessions[*key].0.is_in_progress() == in_progress
+            })
+            .map(|(tick, key)| (*tick, key.clone()))
+    }
+}
+
+/// Fails with `StoreError::Conflict` unless `stored` has the version `session` was read with.
+pub fn check_version(
+    stored: Option<&GameSession>,
+    session: &GameSession,
+) -> Result<(), StoreError> {
+    let stored_version = stored.map(|s| s.version).unwrap_or_default();
+    if stored_version == session.version {
+        Ok(())
+    } else {
+        Err(StoreError::Conflict(stored_version))
+    }
+}
+
+/// The copy of `session` to keep in a repository.
+pub fn next_version(session: &GameSession) -> GameSession {
+    GameSession {
+        version: session.version + 1,
+        ..session.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::game_engine::game_def::QuestionId;
+    use crate::game_engine::types::{
+        GameSession, PlayerId, SessionRepository, SessionState, StoreError,
+    };
+    use crate::services::sessions::InMemorySessionRepository;
+
+    fn make_session() -> GameSession {
+        player_session("a", SessionState::New, 0)
+    }
+
+    fn player_session(id: &str, state: SessionState, last_activity: u64) -> GameSession {
+        let mut session = GameSession::new(
+            &PlayerId {
+                channel_id: "1".to_string(),
+                id: id.to_string(),
+            },
+            1,
+        );
+        session.state = state;
+        session.last_activity = last_activity;
+        session
+    }
+
+    async fn is_stored(repo: &InMemorySessionRepository, id: &str) -> bool {
+        let player_id = player_session(id, SessionState::New, 0).player_id;
+        repo.get_by_id(1, &player_id).await.is_some()
+    }
+
+    #[tokio::test]
+    async fn stale_session_is_not_stored() {
+        let repo = InMemorySessionRepository::default();
+        let session = make_session();
+        assert_eq!(Ok(()), repo.store(&session).await);
+
+        let mut stored = repo.get_by_id(1, &session.player_id).await.unwrap();
+        assert_eq!(1, stored.version);
+        stored.score = 1;
+        assert_eq!(Ok(()), repo.store(&stored).await);
+        assert_eq!(Err(StoreError::Conflict(2)), repo.store(&session).await);
+        assert_eq!(Err(StoreError::Conflict(2)), repo.store(&stored).await);
+        assert_eq!(
+            Some(1),
+            repo.get_by_id(1, &session.player_id).await.map(|s| s.score)
+        );
+    }
+
+    #[tokio::test]
+    async fn idle_sessions_of_players_not_playing_expire() {
+        let repo = InMemorySessionRepository::default().with_ttl(Duration::from_secs(100));
+        let answering = SessionState::answering(QuestionId::default(), 0);
+        for (id, state) in [
+            ("new", SessionState::New),
+            ("deciding", SessionState::Deciding),
+            ("terminated", SessionState::Terminated),
+            ("answering", answering),
+            ("choosing", SessionState::ChoosingTopic),
+            ("complete", SessionState::Complete),
+        ] {
+            repo.store(&player_session(id, state, 1000)).await.unwrap();
+        }
+        repo.store(&player_session("recent", SessionState::New, 1950))
+            .await
+            .unwrap();
+
+        repo.evict_expired(2000);
+        assert_eq!(4, repo.len());
+        for id in ["answering", "choosing", "complete", "recent"] {
+            assert!(is_stored(&repo, id).await, "{} was evicted", id);
+        }
+    }
+
+    #[tokio::test]
+    async fn least_recently_stored_sessions_are_evicted_when_full() {
+        let repo = InMemorySessionRepository::default()
+            .with_shards(1)
+            .with_capacity(2);
+        let mut a = player_session("a", SessionState::ChoosingTopic, 0);
+        repo.store(&a).await.unwrap();
+        repo.store(&player_session("b", SessionState::New, 0))
+            .await
+            .unwrap();
+        a.version = 1;
+        repo.store(&a).await.unwrap();
+        repo.store(&player_session("c", SessionState::New, 0))
+            .await
+            .unwrap();
+
+        assert_eq!(2, repo.len());
+        assert!(is_stored(&repo, "a").await);
+        assert!(!is_stored(&repo, "b").await);
+        assert!(is_stored(&repo, "c").await);
+    }
+
+    #[tokio::test]
+    async fn capacity_applies_to_all_shards() {
+        let repo = InMemorySessionRepository::default()
+            .with_shards(4)
+            .with_capacity(40);
+        for num in 0..1000 {
+            repo.store(&player_session(
+                num.to_string().as_str(),
+                SessionState::New,
+                0,
+            ))
+            .await
+            .unwrap();
+        }
+        assert_eq!(40, repo.len());
+        assert!(is_stored(&repo, "999").await);
+        assert!(!is_stored(&repo, "959").await);
+    }
+
+    #[tokio::test]
+    async fn sessions_in_progress_are_evicted_last() {
+        let repo = InMemorySessionRepository::default().with_capacity(3);
+        for id in ["a", "b"] {
+            repo.store(&player_session(id, SessionState::ChoosingTopic, 0))
+                .await
+                .unwrap();
+        }
+        for id in ["c", "d", "e"] {
+            repo.store(&player_session(id, SessionState::New, 0))
+                .await
+                .unwrap();
+        }
+        assert_eq!(3, repo.len());
+        for id in ["a", "b", "e"] {
+            assert!(is_stored(&repo, id).await, "{} was evicted", id);
+        }
+
+        repo.store(&player_session("f", SessionState::ChoosingTopic, 0))
+            .await
+            .unwrap();
+        assert_eq!(3, repo.len());
+        assert!(!is_stored(&repo, "e").await);
 
-    async fn store(&self, session: &GameSession) {
-        let mut l = self.store.write().unwrap();
-        if !l.contains_key(&session.game_id) {
-            l.insert(session.game_id, Default::default());
+        repo.store(&player_session("g", SessionState::ChoosingTopic, 0))
+            .await
+            .unwrap();
+        assert_eq!(3, repo.len());
+        for id in ["b", "f", "g"] {
+            assert!(is_stored(&repo, id).await, "{} was evicted", id);
         }
-        l.get_mut(&session.game_id)
-            .unwrap()
-            .insert(session.player_id.clone(), session.clone());
     }
 }
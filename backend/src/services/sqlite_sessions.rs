@@ -0,0 +1,563 @@
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use rusqlite::{params, Connection, OptionalExtension, Transaction, TransactionBehavior};
+
+use crate::game_engine::types::{
+    unix_time, GameId, GameSession, PlayerId, SessionRepository, SessionState, StoreError,
+    TopicResult,
+};
+
+/// Schema changes, applied in order. The number of applied ones is kept in
+/// `PRAGMA user_version`, so a migration must never be edited once released.
+const MIGRATIONS: &[&str] = &[
+    r#"
+    CREATE TABLE sessions (
+        game_id INTEGER NOT NULL,
+        channel_id TEXT NOT NULL,
+        player_id TEXT NOT NULL,
+        state TEXT NOT NULL,
+        score INTEGER NOT NULL,
+        unreachable INTEGER NOT NULL,
+        started_at INTEGER NOT NULL,
+        updated_at INTEGER NOT NULL,
+        completed_at INTEGER,
+        PRIMARY KEY (game_id, channel_id, player_id)
+    );
+    CREATE INDEX sessions_completed_at ON sessions (game_id, completed_at);
+    CREATE TABLE topic_results (
+        game_id INTEGER NOT NULL,
+        channel_id TEXT NOT NULL,
+        player_id TEXT NOT NULL,
+        position INTEGER NOT NULL,
+        topic_id INTEGER NOT NULL,
+        score INTEGER NOT NULL,
+        recorded_at INTEGER NOT NULL,
+        PRIMARY KEY (game_id, channel_id, player_id, position)
+    );
+"#,
+    r#"
+    ALTER TABLE sessions ADD COLUMN version INTEGER NOT NULL DEFAULT 0;
+"#,
+    r#"
+    ALTER TABLE sessions ADD COLUMN last_activity INTEGER NOT NULL DEFAULT 0;
+"#,
+    r#"
+    ALTER TABLE sessions ADD COLUMN history TEXT NOT NULL DEFAULT '[]';
+"#,
+    r#"
+    ALTER TABLE topic_results ADD COLUMN questions INTEGER NOT NULL DEFAULT 1;
+"#,
+    r#"
+    ALTER TABLE sessions ADD COLUMN final_score INTEGER;
+"#,
+    r#"
+    CREATE TABLE session_attempts (
+        game_id INTEGER NOT NULL,
+        channel_id TEXT NOT NULL,
+        player_id TEXT NOT NULL,
+        attempt INTEGER NOT NULL,
+        state TEXT NOT NULL,
+        score INTEGER NOT NULL,
+        unreachable INTEGER NOT NULL,
+        started_at INTEGER NOT NULL,
+        updated_at INTEGER NOT NULL,
+        completed_at INTEGER,
+        version INTEGER NOT NULL,
+        last_activity INTEGER NOT NULL,
+        history TEXT NOT NULL,
+        final_score INTEGER,
+        PRIMARY KEY (game_id, channel_id, player_id, attempt)
+    );
+    INSERT INTO session_attempts
+    SELECT game_id, channel_id, player_id, json_array_length(history), state, score,
+           unreachable, started_at, updated_at, completed_at, version, last_activity,
+           history, final_score
+    FROM sessions;
+    DROP TABLE sessions;
+    ALTER TABLE session_attempts RENAME TO sessions;
+    CREATE INDEX sessions_completed_at ON sessions (game_id, completed_at);
+    CREATE TABLE attempt_results (
+        game_id INTEGER NOT NULL,
+        channel_id TEXT NOT NULL,
+        player_id TEXT NOT NULL,
+        attempt INTEGER NOT NULL,
+        position INTEGER NOT NULL,
+        topic_id INTEGER NOT NULL,
+        score INTEGER NOT NULL,
+        recorded_at INTEGER NOT NULL,
+        questions INTEGER NOT NULL,
+        PRIMARY KEY (game_id, channel_id, player_id, attempt, position)
+    );
+    INSERT INTO attempt_results
+    SELECT r.game_id, r.channel_id, r.player_id, coalesce(s.attempt, 0), r.position,
+           r.topic_id, r.score, r.recorded_at, r.questions
+    FROM topic_results r LEFT JOIN sessions s
+        ON s.game_id = r.game_id AND s.channel_id = r.channel_id AND s.player_id = r.player_id;
+    DROP TABLE topic_results;
+    ALTER TABLE attempt_results RENAME TO topic_results;
+"#,
+];
+
+/// Stores sessions in an embedded SQLite database. Besides the session
+/// itself every topic result gets its own row in `topic_results`, and
+/// `sessions.completed_at` tells when the player finished the game and
+/// `sessions.final_score` the score that counts under the replay policy.
+///
+/// Every attempt to play the game has its own rows in both tables, numbered
+/// by the attempts before it in the session history, so replaying a game
+/// keeps the results and completion time of the earlier attempts.
+/// Queries run on the blocking thread pool.
+pub struct SqliteSessionRepository {
+    store: Arc<SqliteStore>,
+}
+
+struct SqliteStore {
+    connection: Mutex<Connection>,
+}
+
+impl SqliteSessionRepository {
+    /// Opens or creates the database and brings its schema up to date.
+    pub fn open(path: &Path) -> anyhow::Result<SqliteSessionRepository> {
+        Self::with_connection(Connection::open(path)?)
+    }
+
+    pub fn open_in_memory() -> anyhow::Result<SqliteSessionRepository> {
+        Self::with_connection(Connection::open_in_memory()?)
+    }
+
+    fn with_connection(mut connection: Connection) -> anyhow::Result<SqliteSessionRepository> {
+        connection.pragma_update(None, "journal_mode", "WAL")?;
+        migrate(&mut connection)?;
+        anyhow::Ok(SqliteSessionRepository {
+            store: Arc::new(SqliteStore {
+                connection: Mutex::new(connection),
+            }),
+        })
+    }
+}
+
+impl SqliteStore {
+    fn load(&self, game_id: GameId, player_id: &PlayerId) -> anyhow::Result<Option<GameSession>> {
+        let connection = self.connection.lock().unwrap();
+        let row = connection
+            .query_row(
+                "SELECT state, score, unreachable, version, last_activity, history, final_score,
+                        attempt
+                 FROM sessions
+                 WHERE game_id = ?1 AND channel_id = ?2 AND player_id = ?3
+                 ORDER BY attempt DESC LIMIT 1",
+                params![game_id, player_id.channel_id, player_id.id],
+                |row| {
+                    Ok((
+                        row.get::<_, String>(0)?,
+                        row.get::<_, i32>(1)?,
+                        row.get::<_, bool>(2)?,
+                        row.get::<_, u32>(3)?,
+                        row.get::<_, u64>(4)?,
+                        row.get::<_, String>(5)?,
+                        row.get::<_, Option<i32>>(6)?,
+                        row.get::<_, usize>(7)?,
+                    ))
+                },
+            )
+            .optional()?;
+        let (state, score, unreachable, version, last_activity, history, final_score, attempt) =
+            match row {
+                Some(row) => row,
+                None => return anyhow::Ok(None),
+            };
+        let mut statement = connection.prepare(
+            "SELECT topic_id, score, questions FROM topic_results
+             WHERE game_id = ?1 AND channel_id = ?2 AND player_id = ?3 AND attempt = ?4
+             ORDER BY position",
+        )?;
+        let results = statement
+            .query_map(
+                params![game_id, player_id.channel_id, player_id.id, attempt],
+                |row| {
+                    Ok(TopicResult {
+                        topic_id: row.get(0)?,
+                        score: row.get(1)?,
+                        questions: row.get(2)?,
+                    })
+                },
+            )?
+            .collect::<Result<Vec<_>, _>>()?;
+        anyhow::Ok(Some(GameSession {
+            player_id: player_id.clone(),
+            game_id,
+            state: serde_json::from_str(state.as_str())?,
+            results,
+            score,
+            unreachable,
+            version,
+            last_activity,
+            history: serde_json::from_str(history.as_str())?,
+            final_score,
+        }))
+    }
+
+    fn save(&self, session: &GameSession) -> anyhow::Result<Result<(), StoreError>> {
+        let mut connection = self.connection.lock().unwrap();
+        let tx = connection.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let player = &session.player_id;
+        let stored_version: u32 = tx
+            .query_row(
+                "SELECT version FROM sessions
+                 WHERE game_id = ?1 AND channel_id = ?2 AND player_id = ?3
+                 ORDER BY attempt DESC LIMIT 1",
+                params![session.game_id, player.channel_id, player.id],
+                |row| row.get(0),
+            )
+            .optional()?
+            .unwrap_or_default();
+        if stored_version != session.version {
+            return anyhow::Ok(Err(StoreError::Conflict(stored_version)));
+        }
+        let now = unix_time();
+        let completed = matches!(session.state, SessionState::Complete);
+        tx.execute(
+            "INSERT INTO sessions (game_id, channel_id, player_id, state, score, unreachable,
+                                   started_at, updated_at, completed_at, version,
+                                   last_activity, history, final_score, attempt)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?7, CASE WHEN ?8 THEN ?7 END, ?9, ?10, ?11, ?12,
+                     ?13)
+             ON CONFLICT (game_id, channel_id, player_id, attempt) DO UPDATE SET
+                 state = excluded.state,
+                 score = excluded.score,
+                 unreachable = excluded.unreachable,
+                 updated_at = excluded.updated_at,
+                 completed_at = CASE WHEN ?8 THEN coalesce(completed_at, ?7) END,
+                 version = excluded.version,
+                 last_activity = excluded.last_activity,
+                 history = excluded.history,
+                 final_score = excluded.final_score",
+            params![
+                session.game_id,
+                player.channel_id,
+                player.id,
+                serde_json::to_string(&session.state)?,
+                session.score,
+                session.unreachable,
+                now,
+                completed,
+                session.version + 1,
+                session.last_activity,
+                serde_json::to_string(&session.history)?,
+                session.final_score,
+                session.history.len(),
+            ],
+        )?;
+        save_results(&tx, session, now)?;
+        tx.commit()?;
+        anyhow::Ok(Ok(()))
+    }
+}
+
+#[async_trait]
+impl SessionRepository for SqliteSessionRepository {
+    async fn get_by_id(&self, game_id: GameId, player_id: &PlayerId) -> Option<GameSession> {
+        let store = self.store.clone();
+        let id = player_id.clone();
+        tokio::task::spawn_blocking(move || store.load(game_id, &id))
+            .await
+            .unwrap_or_else(|err| Err(err.into()))
+            .unwrap_or_else(|err| {
+                log::error!("Failed to load session of {:?} {}", player_id, err);
+                None
+            })
+    }
+
+    async fn store(&self, session: &GameSession) -> Result<(), StoreError> {
+        let store = self.store.clone();
+        let session = session.clone();
+        tokio::task::spawn_blocking(move || store.save(&session))
+            .await
+            .unwrap_or_else(|err| Err(err.into()))
+            .unwrap_or_else(|err| Err(StoreError::Failed(err.to_string())))
+    }
+}
+
+/// Keeps the rows of results already recorded, so `recorded_at` tells when
+/// the topic was actually played.
+fn save_results(tx: &Transaction, session: &GameSession, now: u64) -> anyhow::Result<()> {
+    let player = &session.player_id;
+    let attempt = session.history.len();
+    tx.execute(
+        "DELETE FROM topic_results
+         WHERE game_id = ?1 AND channel_id = ?2 AND player_id = ?3 AND attempt = ?4
+             AND position >= ?5",
+        params![
+            session.game_id,
+            player.channel_id,
+            player.id,
+            attempt,
+            session.results.len()
+        ],
+    )?;
+    let mut statement = tx.prepare(
+        "INSERT INTO topic_results (game_id, channel_id, player_id, attempt, position, topic_id,
+                                    score, recorded_at, questions)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)
+         ON CONFLICT (game_id, channel_id, player_id, attempt, position) DO UPDATE SET
+             topic_id = excluded.topic_id,
+             score = excluded.score,
+             questions = excluded.questions,
+             recorded_at = CASE WHEN topic_id = excluded.topic_id AND score = excluded.score
+                                THEN recorded_at ELSE excluded.recorded_at END",
+    )?;
+    for (position, result) in session.results.iter().enumerate() {
+        statement.execute(params![
+            session.game_id,
+            player.channel_id,
+            player.id,
+            attempt,
+            position,
+            result.topic_id,
+            result.score,
+            now,
+            result.questions,
+        ])?;
+    }
+    anyhow::Ok(())
+}
+
+fn migrate(connection: &mut Connection) -> anyhow::Result<()> {
+    let version: usize = connection.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    if version > MIGRATIONS.len() {
+        anyhow::bail!(
+            "Database schema version {} is newer than supported {}",
+            version,
+            MIGRATIONS.len()
+        );
+    }
+    for (num, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        log::info!("Migrating sessions database to version {}", num + 1);
+        let tx = connection.transaction()?;
+        tx.execute_batch(migration)?;
+        tx.pragma_update(None, "user_version", num + 1)?;
+        tx.commit()?;
+    }
+    anyhow::Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::game_engine::game_def::QuestionId;
+    use crate::game_engine::types::{
+        GameSession, PlayerId, SessionRepository, SessionState, StoreError, TopicResult,
+    };
+    use rusqlite::Connection;
+
+    use crate::services::sqlite_sessions::{SqliteSessionRepository, MIGRATIONS};
+
+    fn make_session(id: &str) -> GameSession {
+        GameSession::new(
+            &PlayerId {
+                channel_id: "1".to_string(),
+                id: id.to_string(),
+            },
+            1,
+        )
+    }
+
+    async fn store(repo: &SqliteSessionRepository, session: &mut GameSession) {
+        repo.store(session).await.unwrap();
+        session.version += 1;
+    }
+
+    #[tokio::test]
+    async fn session_is_stored_and_loaded() {
+        let repo = SqliteSessionRepository::open_in_memory().unwrap();
+        let mut session = make_session("a");
+        assert_eq!(None, repo.get_by_id(1, &session.player_id).await);
+
+        session.record(1, 3);
+        session.restart();
+        session.state = SessionState::answering(QuestionId::default(), 1);
+        session.record(0, 2);
+        session.record_round(1, 4, 3);
+        session.unreachable = true;
+        store(&repo, &mut session).await;
+
+        assert_eq!(
+            Some(session.clone()),
+            repo.get_by_id(1, &session.player_id).await
+        );
+        assert_eq!(None, repo.get_by_id(2, &session.player_id).await);
+    }
+
+    #[tokio::test]
+    async fn stale_session_is_not_stored() {
+        let repo = SqliteSessionRepository::open_in_memory().unwrap();
+        let mut session = make_session("a");
+        store(&repo, &mut session).await;
+        let mut stale = make_session("a");
+        stale.record(0, 2);
+
+        assert_eq!(Err(StoreError::Conflict(1)), repo.store(&stale).await);
+        assert_eq!(
+            Some(session.clone()),
+            repo.get_by_id(1, &session.player_id).await
+        );
+    }
+
+    #[tokio::test]
+    async fn results_are_kept_in_their_own_table() {
+        let repo = SqliteSessionRepository::open_in_memory().unwrap();
+        let mut session = make_session("a");
+        session.record(1, 2);
+        store(&repo, &mut session).await;
+        session.record(0, 1);
+        session.state = SessionState::Complete;
+        session.final_score = Some(3);
+        store(&repo, &mut session).await;
+        store(&repo, &mut make_session("b")).await;
+
+        let connection = repo.store.connection.lock().unwrap();
+        let results: Vec<(String, u8, u8)> = connection
+            .prepare("SELECT player_id, topic_id, score FROM topic_results ORDER BY position")
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+            .unwrap()
+            .map(|r| r.unwrap())
+            .collect();
+        assert_eq!(
+            vec![("a".to_string(), 1, 2), ("a".to_string(), 0, 1)],
+            results
+        );
+        let finished: Vec<(String, u16, u16)> = connection
+            .prepare(
+                "SELECT player_id, score, final_score FROM sessions
+                 WHERE completed_at IS NOT NULL",
+            )
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+            .unwrap()
+            .map(|r| r.unwrap())
+            .collect();
+        assert_eq!(vec![("a".to_string(), 3, 3)], finished);
+    }
+
+    #[tokio::test]
+    async fn earlier_attempts_are_kept() {
+        let repo = SqliteSessionRepository::open_in_memory().unwrap();
+        let mut session = make_session("a");
+        session.record(1, 2);
+        session.state = SessionState::Complete;
+        session.final_score = Some(2);
+        store(&repo, &mut session).await;
+        session.restart();
+        session.state = SessionState::ChoosingTopic;
+        session.record(0, 1);
+        store(&repo, &mut session).await;
+
+        assert_eq!(
+            Some(session.clone()),
+            repo.get_by_id(1, &session.player_id).await
+        );
+        let connection = repo.store.connection.lock().unwrap();
+        let attempts: Vec<(u8, u16, bool)> = connection
+            .prepare(
+                "SELECT attempt, score, completed_at IS NOT NULL FROM sessions ORDER BY attempt",
+            )
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+            .unwrap()
+            .map(|r| r.unwrap())
+            .collect();
+        assert_eq!(vec![(0, 2, true), (1, 1, false)], attempts);
+        let results: Vec<(u8, u8, u8)> = connection
+            .prepare("SELECT attempt, topic_id, score FROM topic_results ORDER BY attempt")
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+            .unwrap()
+            .map(|r| r.unwrap())
+            .collect();
+        assert_eq!(vec![(0, 1, 2), (1, 0, 1)], results);
+    }
+
+    #[tokio::test]
+    async fn rows_of_earlier_versions_become_the_latest_attempt() {
+        let connection = Connection::open_in_memory().unwrap();
+        for migration in MIGRATIONS.iter().take(6) {
+            connection.execute_batch(migration).unwrap();
+        }
+        connection
+            .execute_batch(
+                r#"
+                PRAGMA user_version = 6;
+                INSERT INTO sessions (game_id, channel_id, player_id, state, score, unreachable,
+                                      started_at, updated_at, version, history)
+                VALUES (1, '1', 'a', '"ChoosingTopic"', 2, 0, 0, 0, 3,
+                        '[{"results":[],"score":0,"complete":false}]');
+                INSERT INTO topic_results (game_id, channel_id, player_id, position, topic_id,
+                                           score, recorded_at)
+                VALUES (1, '1', 'a', 0, 1, 2, 0);
+                "#,
+            )
+            .unwrap();
+        let repo = SqliteSessionRepository::with_connection(connection).unwrap();
+
+        let session = repo
+            .get_by_id(1, &make_session("a").player_id)
+            .await
+            .unwrap();
+        assert_eq!(1, session.history.len());
+        assert_eq!(
+            vec![TopicResult {
+                topic_id: 1,
+                score: 2,
+                questions: 1,
+            }],
+            session.results
+        );
+        assert_eq!(3, session.version);
+    }
+
+    #[tokio::test]
+    async fn removed_results_are_deleted() {
+        let repo = SqliteSessionRepository::open_in_memory().unwrap();
+        let mut session = make_session("a");
+        session.record(1, 2);
+        store(&repo, &mut session).await;
+        session.results.clear();
+        store(&repo, &mut session).await;
+
+        assert_eq!(
+            Some(session.clone()),
+            repo.get_by_id(1, &session.player_id).await
+        );
+    }
+
+    #[tokio::test]
+    async fn database_survives_reopening() {
+        let path = std::env::temp_dir().join(format!("quiz-{}.db", rand::random::<u64>()));
+        let mut session = make_session("a");
+        session.record(0, 1);
+        {
+            let repo = SqliteSessionRepository::open(&path).unwrap();
+            store(&repo, &mut session).await;
+        }
+
+        let repo = SqliteSessionRepository::open(&path).unwrap();
+        assert_eq!(
+            Some(session.clone()),
+            repo.get_by_id(1, &session.player_id).await
+        );
+        let version: usize = repo
+            .store
+            .connection
+            .lock()
+            .unwrap()
+            .query_row("PRAGMA user_version", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(MIGRATIONS.len(), version);
+        drop(repo);
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", path.display(), suffix));
+        }
+    }
+}
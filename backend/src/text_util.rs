@@ -6,3 +6,201 @@ static MEANINGLESS_SYMBOLS: Lazy<Regex> = Lazy::new(|| Regex::new("(?i)[^a-zа-
 pub fn answer_to_standard(text: &str) -> String {
     MEANINGLESS_SYMBOLS.replace(text, "").to_lowercase()
 }
+
+static WORD: Lazy<Regex> = Lazy::new(|| Regex::new("(?i)[a-zа-яё0-9]+").unwrap());
+
+/// Lowercase words of the text, without punctuation.
+pub fn words(text: &str) -> Vec<String> {
+    WORD.find_iter(text)
+        .map(|m| m.as_str().to_lowercase())
+        .collect()
+}
+
+/// Levenshtein distance counted in characters.
+pub fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1; b.len() + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = prev[j] + usize::from(ca != *cb);
+            current[j + 1] = substitution.min(prev[j + 1] + 1).min(current[j] + 1);
+        }
+        prev = current;
+    }
+    prev[b.len()]
+}
+
+/// Either digits in groups of three split by commas, as in "10,000", or
+/// digits with a decimal point or comma, as in "2,5".
+static NUMBER: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"(?P<grouped>-?\d{1,3}(?:,\d{3})+\b(?:\.\d+)?)|(?P<plain>-?\d+(?:[.,]\d+)?)")
+        .unwrap()
+});
+
+/// Digits in groups of three split by spaces, as in "1 000", and nothing else.
+static SPACED_NUMBER: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"^-?\d{1,3}(?: \d{3})+(?:[.,]\d+)?$").unwrap());
+
+/// The first number in the text, written in digits or in English or Russian words.
+/// Spaces split the groups of digits only when the whole text is the number,
+/// so "100 200" is read as 100.
+pub fn extract_number(text: &str) -> Option<f64> {
+    let text = text.trim();
+    if SPACED_NUMBER.is_match(text) {
+        return text.replace(' ', "").replace(',', ".").parse().ok();
+    }
+    match NUMBER.captures(text) {
+        Some(c) => match (c.name("grouped"), c.name("plain")) {
+            (Some(m), _) => m.as_str().replace(',', "").parse().ok(),
+            (_, Some(m)) => m.as_str().replace(',', ".").parse().ok(),
+            _ => None,
+        },
+        None => number_from_words(words(text).iter().map(|w| w.as_str())),
+    }
+}
+
+enum NumberWord {
+    Value(u64),
+    Hundred,
+    Scale(u64),
+    /// "and" between the parts of a number, as in "three hundred and twelve".
+    Joint,
+}
+
+/// `None` when there are no number words, or the number does not fit in `u64`.
+fn number_from_words<'a>(words: impl Iterator<Item = &'a str>) -> Option<f64> {
+    let mut total: u64 = 0;
+    let mut current: u64 = 0;
+    let mut found = false;
+    for word in words {
+        match (number_word(word), found) {
+            (Some(NumberWord::Value(value)), _) => current = current.checked_add(value)?,
+            (Some(NumberWord::Hundred), _) => current = current.max(1).checked_mul(100)?,
+            (Some(NumberWord::Scale(scale)), _) => {
+                total = current
+                    .max(1)
+                    .checked_mul(scale)
+                    .and_then(|value| value.checked_add(total))?;
+                current = 0;
+            }
+            (Some(NumberWord::Joint), true) => continue,
+            (_, true) => break,
+            (_, false) => continue,
+        }
+        found = true;
+    }
+    if found {
+        total.checked_add(current).map(|value| value as f64)
+    } else {
+        None
+    }
+}
+
+fn number_word(word: &str) -> Option<NumberWord> {
+    let value = match word {
+        "zero" | "ноль" => 0,
+        "one" | "один" | "одна" | "одно" => 1,
+        "two" | "два" | "две" => 2,
+        "three" | "три" => 3,
+        "four" | "четыре" => 4,
+        "five" | "пять" => 5,
+        "six" | "шесть" => 6,
+        "seven" | "семь" => 7,
+        "eight" | "восемь" => 8,
+        "nine" | "девять" => 9,
+        "ten" | "десять" => 10,
+        "eleven" | "одиннадцать" => 11,
+        "twelve" | "двенадцать" => 12,
+        "thirteen" | "тринадцать" => 13,
+        "fourteen" | "четырнадцать" => 14,
+        "fifteen" | "пятнадцать" => 15,
+        "sixteen" | "шестнадцать" => 16,
+        "seventeen" | "семнадцать" => 17,
+        "eighteen" | "восемнадцать" => 18,
+        "nineteen" | "девятнадцать" => 19,
+        "twenty" | "двадцать" => 20,
+        "thirty" | "тридцать" => 30,
+        "forty" | "сорок" => 40,
+        "fifty" | "пятьдесят" => 50,
+        "sixty" | "шестьдесят" => 60,
+        "seventy" | "семьдесят" => 70,
+        "eighty" | "восемьдесят" => 80,
+        "ninety" | "девяносто" => 90,
+        "сто" => 100,
+        "двести" => 200,
+        "триста" => 300,
+        "четыреста" => 400,
+        "пятьсот" => 500,
+        "шестьсот" => 600,
+        "семьсот" => 700,
+        "восемьсот" => 800,
+        "девятьсот" => 900,
+        "hundred" => return Some(NumberWord::Hundred),
+        "thousand" | "тысяча" | "тысячи" | "тысяч" => {
+            return Some(NumberWord::Scale(1_000))
+        }
+        "million" | "миллион" | "миллиона" | "миллионов" => {
+            return Some(NumberWord::Scale(1_000_000))
+        }
+        "and" => return Some(NumberWord::Joint),
+        _ => return None,
+    };
+    Some(NumberWord::Value(value))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::text_util::{edit_distance, extract_number, words};
+
+    #[test]
+    fn words_are_lowercased_without_punctuation() {
+        assert_eq!(
+            vec!["лев", "толстой", "1828"],
+            words("Лев  Толстой, (1828)!")
+        );
+    }
+
+    #[test]
+    fn edit_distance_counts_characters() {
+        assert_eq!(0, edit_distance("tolstoy", "tolstoy"));
+        assert_eq!(1, edit_distance("dostoevsky", "dostoyevsky"));
+        assert_eq!(2, edit_distance("толстой", "толстый2"));
+        assert_eq!(3, edit_distance("", "abc"));
+    }
+
+    #[test]
+    fn numbers_are_extracted_from_digits() {
+        assert_eq!(Some(1812.0), extract_number("in 1812"));
+        assert_eq!(Some(1812.0), extract_number("1812 год"));
+        assert_eq!(Some(2.5), extract_number("about 2,5 km"));
+        assert_eq!(Some(-40.0), extract_number("-40 degrees"));
+        assert_eq!(Some(10000.0), extract_number("10,000 men"));
+        assert_eq!(Some(1000.0), extract_number(" 1 000 "));
+        assert_eq!(Some(-1000000.5), extract_number("-1 000 000,5"));
+        assert_eq!(Some(1.0), extract_number("1 000 рублей"));
+        assert_eq!(Some(300.0), extract_number("300 400 km"));
+        assert_eq!(Some(100.0), extract_number("between 100 200"));
+        assert_eq!(Some(1234567.5), extract_number("1,234,567.5"));
+        assert_eq!(Some(1.5), extract_number("1,5000"));
+        assert_eq!(None, extract_number("no idea"));
+    }
+
+    #[test]
+    fn numbers_are_extracted_from_words() {
+        assert_eq!(Some(300.0), extract_number("about three hundred"));
+        assert_eq!(
+            Some(1812.0),
+            extract_number("one thousand eight hundred and twelve years")
+        );
+        assert_eq!(Some(300.0), extract_number("примерно триста"));
+        assert_eq!(Some(1812.0), extract_number("тысяча восемьсот двенадцать"));
+        assert_eq!(Some(2000.0), extract_number("две тысячи"));
+        assert_eq!(Some(1000000.0), extract_number("a million"));
+        assert_eq!(None, extract_number("hundred ".repeat(20).as_str()));
+        assert_eq!(
+            None,
+            extract_number(format!("{} million", "hundred ".repeat(9)).as_str())
+        );
+    }
+}